@@ -18,19 +18,116 @@ Key features:
 from __future__ import annotations
 
 import html
+import io
+import itertools
 import re
+import sys
+import threading
+import time
+from collections import OrderedDict
 from contextlib import contextmanager
-from typing import Any, Iterator
+from html.parser import HTMLParser
+from typing import Any, Callable, Iterator
 
-__all__ = ["Document"]
+__all__ = [
+    "Document",
+    "Element",
+    "Fragment",
+    "Node",
+    "RawNode",
+    "TextNode",
+    "clear_fragment_cache",
+    "register_component",
+    "sse_event",
+    "turbo_stream",
+]
 
 # Pre-compiled regex for attribute name conversion
 _ATTR_NAME_PATTERN = re.compile(r"(\w)_(\w)")
 
+# Characters that need escaping, to skip html.escape() for plain strings
+_ATTR_SPECIAL_CHARS = re.compile(r"[&<>\"']")
+_TEXT_SPECIAL_CHARS = re.compile(r"[&<>]")
+
+# Cache for tag name resolution, see _tag_info()
+_TAG_INFO_CACHE: dict[str, tuple[str, bool]] = {}
+
+# Identifies the calling thread, see Document._check_thread()
+_get_ident = threading.get_ident
+
+# Cache of shared opening/closing tag strings, see _tag_strings()
+_TAG_STRINGS_CACHE: dict[tuple[str, bool], tuple[str, str]] = {}
+
 # Cache for attribute name conversions to avoid repeated regex operations
 _ATTR_NAME_CACHE: dict[str, str] = {}
 
 
+# HTML void elements, which are self-closing and cannot have content
+_VOID_ELEMENTS = frozenset({
+    "area", "base", "br", "col", "embed", "hr", "img", "input",
+    "link", "meta", "param", "source", "track", "wbr"
+})
+
+# MathML namespace, declared on the <math> root element
+MATHML_NAMESPACE = "http://www.w3.org/1998/Math/MathML"
+
+# MathML elements without content, serialized self-closed like void elements
+_MATHML_EMPTY_ELEMENTS = frozenset({
+    "mspace", "mprescripts", "none", "malignmark", "maligngroup"
+})
+
+# Elements written immediately as self-closing tags
+_SELF_CLOSING_ELEMENTS = _VOID_ELEMENTS | _MATHML_EMPTY_ELEMENTS
+
+# Valid custom element names: lowercase ASCII letter first, at least one hyphen
+_CUSTOM_ELEMENT_NAME_PATTERN = re.compile(r"^[a-z][a-z0-9._\-]*-[a-z0-9._\-]*$")
+
+# Raw-text elements whose content is not entity-decoded by browsers
+_RAW_TEXT_ELEMENTS = frozenset({"script", "style"})
+
+# Every letter-case spelling of the raw-text element names, so text() can
+# match open tag names without lowercasing them on each call
+_RAW_TEXT_NAMES = frozenset(
+    "".join(chars)
+    for name in _RAW_TEXT_ELEMENTS
+    for chars in itertools.product(*((char, char.upper()) for char in name))
+)
+
+# Sequences that would prematurely end a raw-text element or switch the
+# HTML tokenizer into a script "escaped" state
+_RAW_TEXT_GUARD_PATTERN = re.compile(r"</(?=script|style)|<!--", re.IGNORECASE)
+
+# CSS property names accepted in style dicts, including custom properties
+_CSS_PROPERTY_PATTERN = re.compile(r"-{0,2}[A-Za-z][A-Za-z0-9-]*")
+
+# Characters that need a closer look in CSS values
+_CSS_VALUE_SPECIAL_CHARS = re.compile(r"[;{}\\\n\r\f\"']")
+
+# Elements whose whitespace is significant, matched whole when minifying
+_WHITESPACE_SENSITIVE_PATTERN = re.compile(
+    r"<(pre|textarea|script|style)\b[^>]*>.*?</\1\s*>",
+    re.IGNORECASE | re.DOTALL,
+)
+
+# Block-level and metadata elements, around which whitespace is insignificant
+_BLOCK_ELEMENTS = frozenset({
+    "html", "head", "body", "title", "meta", "link", "base", "script", "style",
+    "noscript", "template", "address", "article", "aside", "blockquote",
+    "details", "dialog", "dd", "div", "dl", "dt", "fieldset", "figcaption",
+    "figure", "footer", "form", "h1", "h2", "h3", "h4", "h5", "h6", "header",
+    "hgroup", "hr", "li", "main", "menu", "nav", "ol", "p", "pre", "search",
+    "section", "summary", "table", "caption", "colgroup", "col", "thead",
+    "tbody", "tfoot", "tr", "td", "th", "ul", "legend", "optgroup", "option",
+})
+
+# Runs of HTML whitespace collapsed when minifying. Unlike \s, this leaves
+# non-breaking and other Unicode spaces alone since they are visible content.
+_WHITESPACE_PATTERN = re.compile(r"[ \t\n\f\r]+")
+
+# Splits rendered HTML into tags, comments and text runs for pretty-printing
+_TOKEN_PATTERN = re.compile(r"(<!--.*?-->|<[^>]*>)", re.DOTALL)
+
+
 def _convert_attr_name(name: str) -> str:
     """Convert Python attribute names to HTML attribute names.
     
@@ -42,101 +139,1300 @@ def _convert_attr_name(name: str) -> str:
     """
     if name not in _ATTR_NAME_CACHE:
         if name in ("class_", "classes"):
-            _ATTR_NAME_CACHE[name] = "class"
+            converted = "class"
         elif name.endswith("_"):
             # Remove trailing underscore for reserved keywords
-            _ATTR_NAME_CACHE[name] = name[:-1]
+            converted = name[:-1]
         else:
             # Convert underscores to hyphens (e.g., data_value -> data-value)
-            _ATTR_NAME_CACHE[name] = _ATTR_NAME_PATTERN.sub(r"\1-\2", name)
+            converted = _ATTR_NAME_PATTERN.sub(r"\1-\2", name)
+        # Interned so every attribute shares a single string per name
+        _ATTR_NAME_CACHE[name] = sys.intern(converted)
     return _ATTR_NAME_CACHE[name]
 
 
+def _tag_info(tag_name: str) -> tuple[str, bool]:
+    """Resolve a tag name to its HTML name and whether it is self-closing.
+    
+    Underscores are converted to hyphens for custom elements. Results are
+    cached since the same few tag names are used over and over, and the
+    name is interned so all elements of a kind share a single string.
+    """
+    name = sys.intern(tag_name.replace("_", "-"))
+    info = (name, name.lower() in _SELF_CLOSING_ELEMENTS)
+    _TAG_INFO_CACHE[tag_name] = info
+    return info
+
+
+def _tag_strings(tag_name: str, self_closing: bool) -> tuple[str, str]:
+    """Return the cached attribute-less opening tag and the closing tag.
+    
+    Sharing these strings across all elements with the same name avoids
+    allocating a new ``<div>``/``</div>`` pair per element, which adds up
+    in large documents.
+    """
+    key = (tag_name, self_closing)
+    strings = _TAG_STRINGS_CACHE.get(key)
+    if strings is None:
+        opening = f"<{tag_name} />" if self_closing else f"<{tag_name}>"
+        strings = (opening, f"</{tag_name}>")
+        _TAG_STRINGS_CACHE[key] = strings
+    return strings
+
+
 def _escape_attr_value(value: Any) -> str:
-    """Escape an attribute value for safe HTML output."""
+    """Escape an attribute value for safe HTML output.
+    
+    Numbers are rendered directly since they never need escaping, and
+    lists/tuples are joined with spaces (e.g. ``["btn", "btn-lg"]`` ->
+    ``btn btn-lg``), skipping None and False items.
+    """
+    value_type = type(value)
+    if value_type is str:
+        return html.escape(value, quote=True) if _ATTR_SPECIAL_CHARS.search(value) else value
+    if value_type is int or value_type is float:
+        return str(value)
     if value is None:
         return ""
+    if value_type is list or value_type is tuple:
+        return " ".join(
+            html.escape(str(item), quote=True)
+            for item in value
+            if item is not None and item is not False
+        )
     return html.escape(str(value), quote=True)
 
 
+def _neutralize_raw_text_match(match: re.Match[str]) -> str:
+    return "<\\/" if match.group() == "</" else "<\\!--"
+
+
+def _guard_raw_text(text: Any) -> str:
+    """Make text safe inside a <script> or <style> element.
+    
+    Entity escaping would corrupt JS/CSS, so instead the termination
+    sequences are neutralized: ``</script`` becomes ``<\\/script`` and
+    ``<!--`` becomes ``<\\!--``.
+    """
+    return _RAW_TEXT_GUARD_PATTERN.sub(_neutralize_raw_text_match, str(text))
+
+
 def _escape_text(text: Any) -> str:
     """Escape text content for safe HTML output."""
     if text is None:
         return ""
-    return html.escape(str(text), quote=False)
+    text = str(text)
+    return html.escape(text, quote=False) if _TEXT_SPECIAL_CHARS.search(text) else text
+
+
+def _format_classes(value: dict[str, Any] | list[Any] | tuple[Any, ...]) -> str:
+    """Serialize conditional classes to a class attribute value.
+    
+    Accepts a dict of class name -> condition, or a list/tuple mixing
+    plain class names and such dicts; only truthy classes are emitted.
+    
+    Example:
+        ["btn", {"active": True, "hidden": False}] -> "btn active"
+    """
+    if type(value) is dict:
+        return " ".join(name for name, enabled in value.items() if enabled)
+    names: list[str] = []
+    for item in value:
+        if type(item) is dict:
+            names.extend(name for name, enabled in item.items() if enabled)
+        elif item is not None and item is not False:
+            names.append(str(item))
+    return " ".join(names)
+
+
+def _check_style_value(prop: str, value: str) -> str:
+    """Ensure a CSS value can't end its declaration or inject new ones.
+    
+    Semicolons and braces are only allowed inside quoted strings (e.g. a
+    quoted data URI), and line breaks, unterminated strings or a trailing
+    backslash are rejected.
+    
+    Raises:
+        ValueError: If the value is not a single safe CSS value
+    """
+    if not _CSS_VALUE_SPECIAL_CHARS.search(value):
+        return value
+    quote = None
+    escaped = False
+    for char in value:
+        if char in "\n\r\f":
+            break
+        if escaped:
+            escaped = False
+        elif char == "\\":
+            escaped = True
+        elif quote is not None:
+            if char == quote:
+                quote = None
+        elif char == '"' or char == "'":
+            quote = char
+        elif char in ";{}":
+            break
+    else:
+        if quote is None and not escaped:
+            return value
+    raise ValueError(f"Unsafe CSS value for '{prop}': {value!r}")
+
+
+def _format_style(declarations: dict[str, Any]) -> str:
+    """Serialize a dict of CSS properties to a declaration string.
+    
+    Underscores in property names are converted to hyphens like other
+    attributes, and None or False values are skipped.
+    
+    Raises:
+        ValueError: If a property name is invalid, or a value could end
+            its declaration, see _check_style_value()
+    
+    Example:
+        {"margin_top": "4px", "color": "red"} -> "margin-top: 4px; color: red"
+    """
+    rendered = []
+    for prop, value in declarations.items():
+        if value is None or value is False:
+            continue
+        prop = prop.replace("_", "-")
+        if not _CSS_PROPERTY_PATTERN.fullmatch(prop):
+            raise ValueError(f"Invalid CSS property name: {prop!r}")
+        rendered.append(f"{prop}: {_check_style_value(prop, str(value))}")
+    return "; ".join(rendered)
+
+
+def _expand_prefixed_attrs(prefix: str, values: dict[str, Any], rendered: list[str], xml: bool) -> None:
+    """Expand a data/aria dict into prefixed attributes appended to rendered.
+    
+    Keys get underscores converted to hyphens. None values are skipped.
+    ARIA states are enumerated strings, so booleans render as "true" or
+    "false" for aria; data booleans follow the usual boolean attribute rules.
+    
+    Example:
+        ("data", {"user_id": 42}) -> data-user-id="42"
+    """
+    for key, value in values.items():
+        if value is None:
+            continue
+        if prefix == "aria" and type(value) is bool:
+            value = "true" if value else "false"
+        elif value is False:
+            continue
+        name = f"{prefix}-{key.replace('_', '-')}"
+        if value is True:
+            rendered.append(f'{name}="{name}"' if xml else name)
+        else:
+            rendered.append(f'{name}="{_escape_attr_value(value)}"')
+
+
+def _resolve_duplicate_attr(name: str, previous: Any, value: Any, duplicates: str) -> Any:
+    """Return the value to keep for an attribute supplied twice.
+    
+    ``class`` values are always merged with a space. Other attributes follow
+    the duplicates policy: "override" keeps the last value, "error" raises
+    ValueError. A None or False value on either side isn't a duplicate.
+    """
+    if previous is None or previous is False:
+        return value
+    if name == "class":
+        classes = list(previous) if type(previous) in (list, tuple) else [previous]
+        classes.extend(value if type(value) in (list, tuple) else [value])
+        return classes
+    if duplicates == "error" and value is not None and value is not False:
+        raise ValueError(f"Duplicate attribute '{name}'")
+    return value
+
+
+def _merge_duplicate_attrs(attrs: dict[str, Any], duplicates: str) -> dict[str, Any]:
+    """Combine attributes supplied twice under the same HTML name.
+    
+    Returns attributes keyed by HTML name, with ``data``/``aria`` dicts
+    expanded. Duplicates are resolved with _resolve_duplicate_attr().
+    """
+    merged: dict[str, Any] = {}
+    for key, value in attrs.items():
+        name = _convert_attr_name(key)
+        if (name == "data" or name == "aria") and type(value) is dict:
+            items = []
+            for sub_key, sub_value in value.items():
+                if name == "aria" and type(sub_value) is bool:
+                    sub_value = "true" if sub_value else "false"
+                items.append((f"{name}-{sub_key.replace('_', '-')}", sub_value))
+        else:
+            items = [(name, value)]
+        for name, value in items:
+            merged[name] = _resolve_duplicate_attr(name, merged.get(name), value, duplicates)
+    return merged
+
+
+def _format_attrs(attrs: dict[str, Any], xml: bool = False, duplicates: str = "override") -> str:
+    """Serialize attributes to a string with a leading space, or ''.
+    
+    Follows HTML boolean attribute rules: True renders the bare attribute
+    name (e.g. ``disabled``), or ``disabled="disabled"`` when xml is set
+    since XML has no bare attributes, while False and None omit it. A dict
+    passed as ``style`` is serialized to a CSS declaration string, dicts
+    of conditional classes are resolved for ``class``, and ``data``/``aria``
+    dicts expand into multiple prefixed attributes. Attributes resolving to
+    the same name (e.g. ``class_`` and ``classes``) are combined following
+    the duplicates policy, see _merge_duplicate_attrs().
+    """
+    if not attrs:
+        return ""
+    if len(attrs) > 1:
+        names = {_ATTR_NAME_CACHE.get(k) or _convert_attr_name(k) for k in attrs}
+        if len(names) < len(attrs) or "data" in names or "aria" in names:
+            attrs = _merge_duplicate_attrs(attrs, duplicates)
+    rendered = []
+    name_cache = _ATTR_NAME_CACHE
+    for k, v in attrs.items():
+        value_type = type(v)
+        if value_type is str:
+            # Fast path for the most common case
+            name = name_cache.get(k) or _convert_attr_name(k)
+            if _ATTR_SPECIAL_CHARS.search(v):
+                v = html.escape(v, quote=True)
+            rendered.append(f'{name}="{v}"')
+            continue
+        if v is None or v is False:
+            continue
+        name = name_cache.get(k) or _convert_attr_name(k)
+        if v is True:
+            rendered.append(f'{name}="{name}"' if xml else name)
+            continue
+        if value_type is dict or value_type is list or value_type is tuple:
+            if name == "class":
+                v = _format_classes(v)
+                if not v:
+                    continue
+            elif name == "style" and value_type is dict:
+                v = _format_style(v)
+            elif (name == "data" or name == "aria") and value_type is dict:
+                _expand_prefixed_attrs(name, v, rendered, xml)
+                continue
+        rendered.append(f'{name}="{_escape_attr_value(v)}"')
+    if not rendered:
+        return ""
+    return " " + " ".join(rendered)
+
+
+def _token_tag_name(token: str) -> str:
+    """Return the lowercase element name of a tag token (e.g. '</div>' -> 'div')."""
+    name = token[2:-1] if token.startswith("</") else token[1:-1]
+    return name.split(None, 1)[0].rstrip("/").lower() if name.strip() else ""
+
+
+def _tokenize(markup: str) -> list[str]:
+    """Split rendered HTML into tag and text tokens.
+    
+    Whitespace-sensitive elements (pre, textarea, script, style) are kept
+    whole as single tokens, so their content is never parsed as markup.
+    """
+    tokens: list[str] = []
+    position = 0
+    for match in _WHITESPACE_SENSITIVE_PATTERN.finditer(markup):
+        tokens.extend(t for t in _TOKEN_PATTERN.split(markup[position:match.start()]) if t)
+        tokens.append(match.group())
+        position = match.end()
+    tokens.extend(t for t in _TOKEN_PATTERN.split(markup[position:]) if t)
+    return tokens
+
+
+def _minify(markup: str) -> str:
+    """Collapse insignificant whitespace in rendered HTML.
+    
+    Whitespace runs are collapsed to a single space, and dropped entirely
+    next to block-level tags. Content of whitespace-sensitive elements
+    (pre, textarea, script, style) is preserved as-is.
+    """
+    tokens = _tokenize(markup)
+    output: list[str] = []
+    for i, token in enumerate(tokens):
+        if token.startswith("<"):
+            output.append(token)
+            continue
+        text = _WHITESPACE_PATTERN.sub(" ", token)
+        if i > 0 and _is_block_token(tokens[i - 1]):
+            text = text.lstrip(" ")
+        if i + 1 < len(tokens) and _is_block_token(tokens[i + 1]):
+            text = text.rstrip(" ")
+        if text:
+            output.append(text)
+    return "".join(output)
+
+
+def _is_block_token(token: str) -> bool:
+    """Whether a token is a tag around which whitespace is insignificant."""
+    if not token.startswith("<"):
+        return False
+    return token.startswith(("<!", "<?")) or _token_tag_name(token) in _BLOCK_ELEMENTS
+
+
+def _prettify(markup: str, indent: int) -> str:
+    """Re-indent compact HTML with one tag or text run per line.
+    
+    Elements whose only content is a single text run are kept on one line
+    (e.g. ``<h1>Title</h1>``) to keep the output readable. Whitespace
+    around text runs is trimmed, except in whitespace-sensitive elements
+    (pre, textarea, script, style) which are written as-is on one line.
+    """
+    tokens = _tokenize(markup)
+    lines: list[str] = []
+    depth = 0
+    i = 0
+    while i < len(tokens):
+        token = tokens[i]
+        pad = " " * (indent * depth)
+        if token.startswith("</"):
+            depth = max(depth - 1, 0)
+            lines.append(" " * (indent * depth) + token)
+        elif (
+            token.startswith(("<!", "<?"))
+            or token.endswith("/>")
+            or _WHITESPACE_SENSITIVE_PATTERN.fullmatch(token)
+        ):
+            lines.append(pad + token)
+        elif token.startswith("<"):
+            name = _token_tag_name(token)
+            if name in _VOID_ELEMENTS:
+                lines.append(pad + token)
+            elif (
+                i + 2 < len(tokens)
+                and not tokens[i + 1].startswith("<")
+                and tokens[i + 2] == f"</{name}>"
+            ):
+                # Single text child: keep the element on one line
+                lines.append(pad + token + tokens[i + 1] + tokens[i + 2])
+                i += 2
+            elif i + 1 < len(tokens) and tokens[i + 1] == f"</{name}>":
+                # Empty element
+                lines.append(pad + token + tokens[i + 1])
+                i += 1
+            else:
+                lines.append(pad + token)
+                depth += 1
+        else:
+            text = token.strip()
+            if text:
+                lines.append(pad + text)
+        i += 1
+    return "\n".join(lines)
 
 
 class _TagContext:
     """Context manager for HTML tags.
     
     This is a lightweight object that handles opening and closing tags
-    with minimal overhead. It supports lazy tag writing to allow dynamic
-    attribute addition via the attr() method.
+    with minimal overhead. The opening tag of a container element is
+    finalized only when its context exits: a slot is reserved in the
+    document parts on enter and filled in on exit, so attributes can be
+    added via attr() at any point inside the block.
     """
     
-    __slots__ = ("_document", "_tag_name", "_self_closing", "_attrs", "_opened")
+    __slots__ = ("_document", "_tag_name", "_self_closing", "_attrs", "_slot", "_strings")
     
     def __init__(self, document: Document, tag_name: str, attrs: dict[str, Any], self_closing: bool = False) -> None:
         self._document = document
         self._tag_name = tag_name
         self._self_closing = self_closing
-        self._attrs = attrs.copy()  # Copy to avoid mutation issues
-        self._opened = False
-    
-    def _ensure_opened(self) -> None:
-        """Ensure the opening tag has been written to the document."""
-        if not self._opened:
-            self._opened = True
-            
-            # Build the opening tag
-            if self._attrs:
-                attr_str = " " + " ".join(
-                    f'{_convert_attr_name(k)}="{_escape_attr_value(v)}"'
-                    for k, v in self._attrs.items()
-                    if v is not None
-                )
-            else:
-                attr_str = ""
-            
-            if self._self_closing:
-                self._document._parts.append(f"<{self._tag_name}{attr_str} />")
-            else:
-                self._document._parts.append(f"<{self._tag_name}{attr_str}>")
+        self._attrs = attrs  # Owned: always a fresh **kwargs dict
+        self._slot = -1
+        self._strings = _tag_strings(tag_name, self_closing)
+    
+    def _opening_tag(self) -> str:
+        """Serialize the opening tag with the current attributes."""
+        if not self._attrs:
+            return self._strings[0]
+        attr_str = _format_attrs(self._attrs, self._document._xml, self._document._duplicate_attrs)
+        if self._self_closing:
+            return f"<{self._tag_name}{attr_str} />"
+        return f"<{self._tag_name}{attr_str}>"
     
     def add_attr(self, name: str, value: Any) -> None:
         """Add an attribute to this tag context.
         
+        The attribute is recorded on the pending opening tag, which is
+        only serialized when the context exits. Setting a name again merges
+        ``class`` values and follows the document's duplicates policy for
+        other attributes.
+        
         Args:
             name: The attribute name
             value: The attribute value
-            
+        
         Raises:
-            RuntimeError: If the tag has already been opened
+            ValueError: If the attribute is already set and the document's
+                duplicates policy is "error"
         """
-        if self._opened:
-            raise RuntimeError(f"Cannot add attribute '{name}' to tag '{self._tag_name}': tag already opened")
-        
-        self._attrs[name] = value
+        attrs = self._attrs
+        duplicates = self._document._duplicate_attrs
+        if name in attrs:
+            value = _resolve_duplicate_attr(_convert_attr_name(name), attrs[name], value, duplicates)
+        elif attrs and duplicates == "error":
+            # Other spellings of the same name (e.g. ``data_x`` and ``data-x``)
+            # are combined on serialization; fail here rather than on exit
+            html_name = _convert_attr_name(name)
+            for key, previous in attrs.items():
+                if _convert_attr_name(key) == html_name:
+                    _resolve_duplicate_attr(html_name, previous, value, duplicates)
+        attrs[name] = value
     
     def __enter__(self) -> _TagContext:
-        if not self._self_closing:
-            self._document._tag_stack.append(self._tag_name)
-            self._document._context_stack.append(self)
+        document = self._document
+        context_stack = document._context_stack
+        lock = None
+        if context_stack:
+            if document._owner != _get_ident():
+                document._check_thread()
+            if context_stack[-1]._self_closing:
+                raise context_stack[-1]._children_error(len(document._parts))
         else:
-            # Self-closing tags are opened immediately
-            self._ensure_opened()
+            # Outermost tag: the document is bound to this thread until
+            # closed. The lock makes checking and taking ownership atomic
+            # when several threads open an outermost tag concurrently.
+            lock = document._lock
+            lock.acquire()
+            if document._blocks and document._owner != _get_ident():
+                lock.release()
+                raise document._thread_error()
+            document._owner = _get_ident()
+        parts = document._parts
+        # Reserve a slot for the opening tag, finalized on exit
+        self._slot = len(parts)
+        parts.append("")
+        document._tag_stack.append(self._tag_name)
+        context_stack.append(self)
+        if lock is not None:
+            lock.release()
         return self
     
     def __exit__(self, exc_type: Any, exc_val: Any, exc_tb: Any) -> None:
+        document = self._document
+        parts = document._parts
+        if exc_type is not None:
+            self._unwind()
+            return
+        context_stack = document._context_stack
+        if not context_stack or (context_stack[-1] is not self and self not in context_stack):
+            raise RuntimeError(
+                f"<{self._tag_name}> was discarded by rollback() or clear() while its "
+                "block was open: call them outside of the blocks they discard"
+            )
+        if self._self_closing and len(parts) > self._slot + 1:
+            # Drop the element and its children, as when unwinding
+            error = self._children_error(self._slot + 1)
+            self._unwind()
+            raise error
+        strings = self._strings
+        if self._attrs:
+            try:
+                opening = self._opening_tag()
+            except Exception:
+                # e.g. an unsafe style value: drop the element so the
+                # document stays usable
+                self._unwind()
+                raise
+        else:
+            opening = strings[0]
+        document._tag_stack.pop()
+        context_stack.pop()
+        parts[self._slot] = opening
         if not self._self_closing:
-            # Ensure the tag is opened before closing
-            self._ensure_opened()
-            
-            closing_tag = self._document._tag_stack.pop()
-            self._document._parts.append(f"</{closing_tag}>")
-            
-            # Remove this context from the stack
-            self._document._context_stack.pop()
+            parts.append(strings[1])
+    
+    def _unwind(self) -> None:
+        """Discard this element after an exception escaped its block.
+        
+        Its opening slot, its content and any tag left open inside it
+        (e.g. entered without ``with``) are dropped, so the document stays
+        usable.
+        """
+        document = self._document
+        if self not in document._context_stack:
+            # Already discarded, e.g. by rollback()
+            return
+        depth = document._context_stack.index(self)
+        del document._context_stack[depth:]
+        del document._tag_stack[depth:]
+        document._truncate(self._slot)
+    
+    def _children_error(self, operation: int) -> RuntimeError:
+        """Build the error for content added inside a void or self-closing element.
+        
+        Args:
+            operation: Index of the offending operation in the document
+        """
+        return RuntimeError(
+            f"<{self._tag_name}> is a void element and cannot have children "
+            f"(in {self._document._open_path()}, operation {operation})"
+        )
+
+
+class _FormContext(_TagContext):
+    """Context manager for <form> elements created with Document.form().
+    
+    A slot is reserved at the start of the form on enter. On exit, once the
+    method is final (it may be set with attr() inside the block), the
+    document's CSRF token is written there as a hidden input for forms
+    whose method is not GET or dialog, which don't submit to the server. Such forms can't be rendered inside a cache()
+    block, which would replay the token to other documents.
+    """
+    
+    __slots__ = ("_csrf_slot",)
+    
+    def __enter__(self) -> _TagContext:
+        super().__enter__()
+        parts = self._document._parts
+        self._csrf_slot = len(parts)
+        parts.append("")
+        return self
+    
+    def __exit__(self, exc_type: Any, exc_val: Any, exc_tb: Any) -> None:
+        document = self._document
+        token = document._csrf_token
+        method = self._attrs.get("method")
+        if (
+            exc_type is None
+            and token is not None
+            and method is not None
+            and str(method).lower() not in ("get", "dialog")
+        ):
+            if any(type(block) is _CacheContext for block in document._blocks):
+                self._unwind()
+                raise RuntimeError(
+                    "A form with a CSRF token cannot be rendered inside a cache() block: "
+                    "the token would be replayed to other documents"
+                )
+            if callable(token):
+                token = token()
+            hidden = {"type": "hidden", "name": document._csrf_field, "value": token}
+            document._parts[self._csrf_slot] = f"<input{_format_attrs(hidden, document._xml)} />"
+        super().__exit__(exc_type, exc_val, exc_tb)
+
+
+class _HeadContext(_TagContext):
+    """Context manager for the <head> element.
+    
+    On enter, it reserves a slot right after <head> where the document's
+    managed head entries (title, meta, stylesheet links) are serialized at
+    render time, wherever in the build they were declared. Being first
+    keeps a charset meta within the bytes browsers scan for it.
+    """
+    
+    __slots__ = ()
+    
+    def __enter__(self) -> _TagContext:
+        super().__enter__()
+        parts = self._document._parts
+        self._document._head_slot = len(parts)
+        parts.append("")
+        return self
+
+
+class Fragment:
+    """A pre-rendered HTML fragment that can be included in other documents.
+    
+    Fragments are produced by Document.fragment() and spliced into another
+    document with Document.include() without being escaped again.
+    """
+    
+    __slots__ = ("_markup",)
+    
+    def __init__(self, markup: str) -> None:
+        self._markup = markup
+    
+    def __str__(self) -> str:
+        return self._markup
+    
+    def __html__(self) -> str:
+        """Return the markup, so markupsafe and Jinja2 don't escape it again."""
+        return self._markup
+    
+    def __repr__(self) -> str:
+        return f"Fragment({self._markup!r})"
+    
+    def __eq__(self, other: object) -> bool:
+        if isinstance(other, Fragment):
+            return self._markup == other._markup
+        return NotImplemented
+    
+    def __hash__(self) -> int:
+        return hash(self._markup)
+
+
+# A component renders itself into the document given its children and props
+Component = Callable[..., None]
+
+# Registry of named components, shared by all documents
+_COMPONENTS: dict[str, Component] = {}
+
+
+def register_component(name: str, func: Component | None = None) -> Any:
+    """Register a callable as a named component.
+    
+    The callable is invoked as ``func(doc, children, **props)``, where
+    children is a Fragment holding the content of the ``with`` block; it
+    decides where to render it via ``doc.include(children)``. Can be used
+    directly or as a decorator.
+    
+    Example:
+        @register_component("card")
+        def card(doc: Document, children: Fragment, title: str) -> None:
+            with doc.div(class_="card"):
+                with doc.h2():
+                    doc.text(title)
+                doc.include(children)
+    """
+    if func is None:
+        def decorator(f: Component) -> Component:
+            _COMPONENTS[name] = f
+            return f
+        return decorator
+    _COMPONENTS[name] = func
+    return func
+
+
+class _ComponentContext:
+    """Context manager invoking a component with the block as its children.
+    
+    The content written inside the block is captured from the document
+    parts on exit and handed to the component as a Fragment. The depth of
+    the tag stack is recorded on enter, so attr() calls that would reach a
+    tag enclosing the block are rejected.
+    """
+    
+    __slots__ = ("_document", "_func", "_props", "_start", "_depth")
+    
+    def __init__(self, document: Document, func: Component, props: dict[str, Any]) -> None:
+        self._document = document
+        self._func = func
+        self._props = props
+        self._start = -1
+        self._depth = -1
+    
+    def __enter__(self) -> _ComponentContext:
+        self._document._bind_thread()
+        self._start = len(self._document._parts)
+        self._depth = len(self._document._context_stack)
+        self._document._blocks.append(self)
+        return self
+    
+    def __exit__(self, exc_type: Any, exc_val: Any, exc_tb: Any) -> None:
+        document = self._document
+        document._leave_block(self)
+        parts = document._parts
+        # The head entries slot can't follow the children into a Fragment
+        head_inside = document._head_slot >= self._start
+        if head_inside:
+            document._head_slot = -1
+        children = Fragment("".join(parts[self._start:]))
+        del parts[self._start:]
+        if exc_type is None:
+            if head_inside:
+                raise RuntimeError("head() cannot be used inside a component block")
+            self._func(document, children, **self._props)
+
+
+class _FragmentCache:
+    """A thread-safe LRU cache of rendered fragments.
+    
+    Keys are a user key paired with the XML mode of the document, since
+    the same block serializes differently in HTML and XML documents.
+    
+    Entries optionally expire after a time-to-live in seconds. Each entry
+    records the CSP nonce of the document that rendered it, see
+    _CacheContext.
+    """
+    
+    __slots__ = ("_entries", "_lock", "maxsize")
+    
+    def __init__(self, maxsize: int = 1024) -> None:
+        self._entries: OrderedDict[tuple[str, bool], tuple[str, str | None, float | None]] = OrderedDict()
+        self._lock = threading.Lock()
+        self.maxsize = maxsize
+    
+    def get(self, key: tuple[str, bool]) -> tuple[str, str | None] | None:
+        with self._lock:
+            entry = self._entries.get(key)
+            if entry is None:
+                return None
+            markup, nonce, expires_at = entry
+            if expires_at is not None and time.monotonic() >= expires_at:
+                del self._entries[key]
+                return None
+            self._entries.move_to_end(key)
+            return markup, nonce
+    
+    def set(self, key: tuple[str, bool], markup: str, nonce: str | None, ttl: float | None) -> None:
+        expires_at = time.monotonic() + ttl if ttl is not None else None
+        with self._lock:
+            self._entries[key] = (markup, nonce, expires_at)
+            self._entries.move_to_end(key)
+            while len(self._entries) > self.maxsize:
+                self._entries.popitem(last=False)
+    
+    def clear(self) -> None:
+        with self._lock:
+            self._entries.clear()
+
+
+# Fragment cache shared by all documents
+_FRAGMENT_CACHE = _FragmentCache()
+
+
+def clear_fragment_cache() -> None:
+    """Drop all fragments cached with Document.cache()."""
+    _FRAGMENT_CACHE.clear()
+
+
+class _CacheContext:
+    """Context manager caching the content written inside its block.
+    
+    On a cache hit, the cached markup is spliced in on enter and anything
+    written in the block is discarded. On a miss, the block content is
+    captured on exit and stored in the fragment cache.
+    
+    Documents usually get a fresh CSP nonce per request, so on a hit the
+    nonce attributes of the cached markup are rewritten to the current
+    document's nonce. Markup cached with a nonce is a miss for documents
+    without one, and vice versa.
+    """
+    
+    __slots__ = ("_document", "_key", "_ttl", "_start", "_hit")
+    
+    def __init__(self, document: Document, key: str, ttl: float | None) -> None:
+        self._document = document
+        self._key = (key, document._xml)
+        self._ttl = ttl
+        self._start = -1
+        self._hit = False
+    
+    def __enter__(self) -> bool:
+        document = self._document
+        document._bind_thread()
+        parts = document._parts
+        cached = _FRAGMENT_CACHE.get(self._key)
+        if cached is not None:
+            markup, cached_nonce = cached
+            nonce = document._nonce
+            if (cached_nonce is None) == (nonce is None):
+                if cached_nonce != nonce:
+                    markup = markup.replace(
+                        f' nonce="{_escape_attr_value(cached_nonce)}"',
+                        f' nonce="{_escape_attr_value(nonce)}"',
+                    )
+                parts.append(markup)
+                self._hit = True
+        self._start = len(parts)
+        document._blocks.append(self)
+        return self._hit
+    
+    def __exit__(self, exc_type: Any, exc_val: Any, exc_tb: Any) -> None:
+        document = self._document
+        document._leave_block(self)
+        parts = document._parts
+        if self._hit:
+            document._truncate(self._start)
+        elif exc_type is not None:
+            # Partial content is discarded, and not cached
+            document._truncate(self._start)
+        elif document._head_slot >= self._start:
+            # The head entries slot can't be replayed from the cached markup
+            document._truncate(self._start)
+            raise RuntimeError("head() cannot be used inside a cache() block")
+        else:
+            _FRAGMENT_CACHE.set(self._key, "".join(parts[self._start:]), document._nonce, self._ttl)
+
+
+class _Snapshot:
+    """Opaque document state captured by Document.snapshot()."""
+    
+    __slots__ = ("_document", "_length", "_contexts", "_attrs", "_head_entries", "_head_slot")
+    
+    def __init__(self, document: Document) -> None:
+        self._document = document
+        self._length = len(document._parts)
+        self._contexts = tuple(document._context_stack)
+        # Open tags are finalized on exit, so their pending attributes too
+        self._attrs = tuple(dict(context._attrs) for context in self._contexts)
+        self._head_entries = dict(document._head_entries)
+        self._head_slot = document._head_slot
+
+
+class Node:
+    """Base class of the element tree nodes produced by Document.to_tree()."""
+    
+    __slots__ = ("parent",)
+    
+    def __init__(self) -> None:
+        self.parent: Element | None = None
+    
+    def remove(self) -> None:
+        """Detach this node from its parent, if any."""
+        if self.parent is not None:
+            self.parent.children.remove(self)
+            self.parent = None
+    
+    def render(self) -> str:
+        """Serialize this node back to HTML."""
+        raise NotImplementedError
+    
+    def __str__(self) -> str:
+        return self.render()
+
+
+class TextNode(Node):
+    """A text node, holding unescaped text."""
+    
+    __slots__ = ("text",)
+    
+    def __init__(self, text: str) -> None:
+        super().__init__()
+        self.text = text
+    
+    def render(self) -> str:
+        if self.parent is not None and self.parent.tag.lower() in _RAW_TEXT_ELEMENTS:
+            return self.text
+        return html.escape(self.text, quote=False)
+    
+    def __repr__(self) -> str:
+        return f"TextNode({self.text!r})"
+
+
+class RawNode(Node):
+    """Markup kept verbatim: doctype, XML declaration or comment."""
+    
+    __slots__ = ("markup",)
+    
+    def __init__(self, markup: str) -> None:
+        super().__init__()
+        self.markup = markup
+    
+    def render(self) -> str:
+        return self.markup
+    
+    def __repr__(self) -> str:
+        return f"RawNode({self.markup!r})"
+
+
+class Element(Node):
+    """An element of the tree built by Document.to_tree().
+    
+    Unlike the flat document buffer, the tree can be modified after the
+    fact (attributes, inserting or removing children) and re-serialized.
+    Attribute values are stored unescaped; None marks a bare boolean
+    attribute. The root returned by to_tree() has an empty tag and only
+    serializes its children.
+    
+    Example:
+        tree = doc.to_tree()
+        nav = tree.children[0]
+        nav.set_attr("class", "sticky")
+        nav.insert(0, "Menu")
+        html = tree.render()
+    """
+    
+    __slots__ = ("tag", "attrs", "children", "self_closing")
+    
+    def __init__(
+        self,
+        tag: str,
+        attrs: dict[str, str | None] | None = None,
+        children: list[Node] | None = None,
+        self_closing: bool = False,
+    ) -> None:
+        super().__init__()
+        self.tag = tag
+        self.attrs: dict[str, str | None] = attrs if attrs is not None else {}
+        self.children: list[Node] = []
+        self.self_closing = self_closing
+        for child in children or ():
+            self.append(child)
+    
+    def get(self, name: str, default: str | None = None) -> str | None:
+        """Return an attribute value (None for bare boolean attributes)."""
+        return self.attrs.get(_convert_attr_name(name), default)
+    
+    def set_attr(self, name: str, value: Any) -> None:
+        """Set an attribute, converting the name like Document attributes.
+        
+        True sets a bare boolean attribute, False or None removes it, and
+        lists/tuples are joined with spaces.
+        """
+        name = _convert_attr_name(name)
+        if value is None or value is False:
+            self.attrs.pop(name, None)
+        elif value is True:
+            self.attrs[name] = None
+        elif type(value) is list or type(value) is tuple:
+            self.attrs[name] = " ".join(str(item) for item in value if item is not None and item is not False)
+        else:
+            self.attrs[name] = str(value)
+    
+    def remove_attr(self, name: str) -> None:
+        """Remove an attribute if present."""
+        self.attrs.pop(_convert_attr_name(name), None)
+    
+    def insert(self, index: int, node: Node | str) -> None:
+        """Insert a child at a position; strings become text nodes.
+        
+        The node is detached from its previous parent first.
+        """
+        if isinstance(node, str):
+            node = TextNode(node)
+        node.remove()
+        node.parent = self
+        self.children.insert(index, node)
+    
+    def append(self, node: Node | str) -> None:
+        """Append a child; strings become text nodes."""
+        self.insert(len(self.children), node)
+    
+    @property
+    def text(self) -> str:
+        """The concatenated text content of this element and its descendants."""
+        return "".join(
+            child.text if isinstance(child, (TextNode, Element)) else ""
+            for child in self.children
+        )
+    
+    def iter(self) -> Iterator[Element]:
+        """Iterate over descendant elements in document order."""
+        for child in self.children:
+            if isinstance(child, Element):
+                yield child
+                yield from child.iter()
+    
+    def find(self, selector: str) -> list[Element]:
+        """Return the descendant elements matching a CSS selector, in document order.
+        
+        Raises:
+            ValueError: If the selector is invalid or uses unsupported syntax
+        
+        Example:
+            links = tree.find("div.card > a[href]")
+        """
+        selectors = _parse_selector(selector)
+        return [
+            element for element in self.iter()
+            if any(_matches_complex(element, complex_selector, len(complex_selector) - 1)
+                   for complex_selector in selectors)
+        ]
+    
+    def find_one(self, selector: str) -> Element | None:
+        """Return the first descendant element matching a CSS selector, or None."""
+        matches = self.find(selector)
+        return matches[0] if matches else None
+    
+    def render(self) -> str:
+        inner = "".join(child.render() for child in self.children)
+        if not self.tag:
+            return inner
+        attrs = "".join(
+            f" {name}" if value is None else f' {name}="{html.escape(value, quote=True)}"'
+            for name, value in self.attrs.items()
+        )
+        if self.self_closing and not self.children:
+            return f"<{self.tag}{attrs} />"
+        return f"<{self.tag}{attrs}>{inner}</{self.tag}>"
+    
+    def fragment(self) -> Fragment:
+        """Serialize this element to a Fragment that can be included in a Document."""
+        return Fragment(self.render())
+    
+    def __repr__(self) -> str:
+        return f"Element({self.tag!r}, {self.attrs!r}, children={len(self.children)})"
+
+
+# A compound selector: tag name (or None for any), then conditions on
+# id, classes and attributes as (name, operator, value)
+_Compound = tuple[str | None, list[str], list[str], list[tuple[str, str, str]]]
+
+# A complex selector: compounds with the combinator preceding each one
+_ComplexSelector = list[tuple[str, _Compound]]
+
+# Tokens of CSS selectors, see _parse_selector()
+_SELECTOR_TOKEN_PATTERN = re.compile(
+    r"""
+    (?P<space>\s*(?P<combinator>[>+~,])\s*|\s+)
+    | (?P<universal>\*)
+    | (?P<type>-?[A-Za-z_][\w-]*)
+    | \#(?P<id>-?[A-Za-z_][\w-]*)
+    | \.(?P<class>-?[A-Za-z_][\w-]*)
+    | \[\s*(?P<attr>[^\s~|^$*=\]]+)\s*
+        (?:(?P<op>[~^$*|]?=)\s*(?:"(?P<dq>[^"]*)"|'(?P<sq>[^']*)'|(?P<bare>[^\s\]]+))\s*)?\]
+    """,
+    re.VERBOSE,
+)
+
+# Parsed selectors, keyed by selector string
+_SELECTOR_CACHE: dict[str, list[_ComplexSelector]] = {}
+
+
+def _parse_selector(selector: str) -> list[_ComplexSelector]:
+    """Parse a CSS selector group into complex selectors.
+    
+    Supports type and universal selectors, #id, .class, attribute
+    selectors ([a], [a=v], [a~=v], [a^=v], [a$=v], [a*=v], [a|=v]), the
+    descendant, child (>), next-sibling (+) and subsequent-sibling (~)
+    combinators, and comma-separated groups.
+    
+    Raises:
+        ValueError: If the selector is invalid or uses unsupported syntax
+    """
+    cached = _SELECTOR_CACHE.get(selector)
+    if cached is not None:
+        return cached
+    
+    groups: list[_ComplexSelector] = []
+    current: _ComplexSelector = []
+    compound: _Compound | None = None
+    combinator = " "
+    position = 0
+    text = selector.strip()
+    
+    def new_compound() -> _Compound:
+        nonlocal compound
+        if compound is None:
+            compound = (None, [], [], [])
+            current.append((combinator, compound))
+        return compound
+    
+    while position < len(text):
+        match = _SELECTOR_TOKEN_PATTERN.match(text, position)
+        if match is None:
+            raise ValueError(f"Unsupported or invalid selector '{selector}' at position {position}")
+        position = match.end()
+        if match.group("space") is not None:
+            if compound is None:
+                raise ValueError(f"Invalid selector '{selector}': unexpected combinator")
+            found = match.group("combinator")
+            compound = None
+            if found == ",":
+                groups.append(current)
+                current = []
+                combinator = " "
+            else:
+                combinator = found or " "
+        elif match.group("universal") is not None:
+            if compound is not None:
+                raise ValueError(f"Invalid selector '{selector}': misplaced '*'")
+            new_compound()
+        elif match.group("type") is not None:
+            if compound is not None:
+                raise ValueError(f"Invalid selector '{selector}': misplaced type selector")
+            compound = (match.group("type").lower(), [], [], [])
+            current.append((combinator, compound))
+        elif match.group("id") is not None:
+            new_compound()[1].append(match.group("id"))
+        elif match.group("class") is not None:
+            new_compound()[2].append(match.group("class"))
+        else:
+            value = match.group("dq")
+            if value is None:
+                value = match.group("sq")
+            if value is None:
+                value = match.group("bare") or ""
+            new_compound()[3].append((match.group("attr").lower(), match.group("op") or "", value))
+    
+    if compound is None:
+        raise ValueError(f"Invalid selector '{selector}': missing selector after combinator")
+    groups.append(current)
+    _SELECTOR_CACHE[selector] = groups
+    return groups
+
+
+def _matches_compound(element: Element, compound: _Compound) -> bool:
+    tag, ids, classes, attrs = compound
+    if tag is not None and element.tag.lower() != tag:
+        return False
+    for element_id in ids:
+        if element.attrs.get("id") != element_id:
+            return False
+    if classes:
+        element_classes = (element.attrs.get("class") or "").split()
+        for class_name in classes:
+            if class_name not in element_classes:
+                return False
+    for name, op, value in attrs:
+        # Names are lowercased by the parser, while the tree keeps the
+        # original case of e.g. SVG's viewBox
+        if name in element.attrs:
+            actual = element.attrs[name] or ""
+        else:
+            for attr_name, attr_value in element.attrs.items():
+                if attr_name.lower() == name:
+                    actual = attr_value or ""
+                    break
+            else:
+                return False
+        if op == "=" and actual != value:
+            return False
+        if op == "~=" and value not in actual.split():
+            return False
+        if op == "^=" and not (value and actual.startswith(value)):
+            return False
+        if op == "$=" and not (value and actual.endswith(value)):
+            return False
+        if op == "*=" and not (value and value in actual):
+            return False
+        if op == "|=" and actual != value and not actual.startswith(value + "-"):
+            return False
+    return True
+
+
+def _previous_elements(element: Element) -> Iterator[Element]:
+    """Iterate over the preceding element siblings, closest first."""
+    if element.parent is None:
+        return
+    siblings = element.parent.children
+    for sibling in reversed(siblings[:siblings.index(element)]):
+        if isinstance(sibling, Element):
+            yield sibling
+
+
+def _matches_complex(element: Element, selector: _ComplexSelector, index: int) -> bool:
+    combinator, compound = selector[index]
+    if not element.tag or not _matches_compound(element, compound):
+        return False
+    if index == 0:
+        return True
+    if combinator == ">":
+        parent = element.parent
+        return parent is not None and _matches_complex(parent, selector, index - 1)
+    if combinator == " ":
+        ancestor = element.parent
+        while ancestor is not None:
+            if _matches_complex(ancestor, selector, index - 1):
+                return True
+            ancestor = ancestor.parent
+        return False
+    if combinator == "+":
+        previous = next(_previous_elements(element), None)
+        return previous is not None and _matches_complex(previous, selector, index - 1)
+    return any(_matches_complex(sibling, selector, index - 1) for sibling in _previous_elements(element))
+
+
+def _same_slot(old: Node, new: Node) -> bool:
+    """Whether two child nodes occupy the same slot and can be diffed in place."""
+    if type(old) is not type(new):
+        return False
+    if isinstance(old, Element):
+        assert isinstance(new, Element)
+        return old.tag == new.tag and old.attrs.get("id") == new.attrs.get("id")
+    return old.render() == new.render()
+
+
+def _diff_element(old: Element, new: Element) -> list[dict[str, Any]] | None:
+    """Compute patch operations turning old into new, keyed by element ids.
+    
+    Returns None when the difference cannot be addressed through ids within
+    this subtree, in which case the caller replaces the nearest element
+    with an id.
+    """
+    if old.tag != new.tag:
+        return None
+    element_id = old.attrs.get("id")
+    if element_id != new.attrs.get("id"):
+        return None
+    
+    patches: list[dict[str, Any]] = []
+    if old.attrs != new.attrs:
+        if element_id is None:
+            return None
+        for name, value in new.attrs.items():
+            if name not in old.attrs or old.attrs[name] != value:
+                patches.append({"op": "set_attr", "target": element_id, "name": name, "value": value})
+        for name in old.attrs:
+            if name not in new.attrs:
+                patches.append({"op": "remove_attr", "target": element_id, "name": name})
+    
+    old_children = old.children
+    if element_id is not None and len(old_children) > len(new.children):
+        # Removed children with an id can be patched out individually
+        new_ids = {c.attrs.get("id") for c in new.children if isinstance(c, Element)}
+        kept = []
+        for child in old_children:
+            child_id = child.attrs.get("id") if isinstance(child, Element) else None
+            if child_id is not None and child_id not in new_ids:
+                patches.append({"op": "remove", "target": child_id})
+            else:
+                kept.append(child)
+        old_children = kept
+    
+    if len(old_children) != len(new.children):
+        return None
+    for old_child, new_child in zip(old_children, new.children):
+        if not _same_slot(old_child, new_child):
+            return None
+        if isinstance(old_child, Element):
+            assert isinstance(new_child, Element)
+            child_patches = _diff_element(old_child, new_child)
+            if child_patches is None:
+                child_id = new_child.attrs.get("id")
+                if child_id is None:
+                    return None
+                child_patches = [{"op": "replace", "target": child_id, "html": new_child.render()}]
+            patches.extend(child_patches)
+    return patches
+
+
+class _TreeBuilder(HTMLParser):
+    """Parses rendered HTML back into an element tree.
+    
+    Tag and attribute name case is recovered from the source since
+    HTMLParser lowercases them.
+    """
+    
+    def __init__(self) -> None:
+        super().__init__(convert_charrefs=True)
+        self.root = Element("")
+        self._stack = [self.root]
+    
+    def _element(self, tag: str, attrs: list[tuple[str, str | None]], self_closing: bool) -> Element:
+        source = self.get_starttag_text() or ""
+        name_match = re.match(r"<\s*([^\s/>]+)", source)
+        tag = name_match.group(1) if name_match else tag
+        element_attrs: dict[str, str | None] = {}
+        for name, value in attrs:
+            original = re.search(r"[\s/]" + re.escape(name) + r"(?=[\s=/>])", source, re.IGNORECASE)
+            element_attrs[original.group()[1:] if original else name] = value
+        element = Element(tag, element_attrs, self_closing=self_closing)
+        self._stack[-1].append(element)
+        return element
+    
+    def handle_starttag(self, tag: str, attrs: list[tuple[str, str | None]]) -> None:
+        if tag in _VOID_ELEMENTS:
+            self._element(tag, attrs, True)
+        else:
+            self._stack.append(self._element(tag, attrs, False))
+    
+    def handle_startendtag(self, tag: str, attrs: list[tuple[str, str | None]]) -> None:
+        self._element(tag, attrs, True)
+    
+    def handle_endtag(self, tag: str) -> None:
+        for i in range(len(self._stack) - 1, 0, -1):
+            if self._stack[i].tag.lower() == tag:
+                del self._stack[i:]
+                return
+    
+    def handle_data(self, data: str) -> None:
+        parent = self._stack[-1]
+        if parent.children and type(parent.children[-1]) is TextNode:
+            parent.children[-1].text += data  # type: ignore[attr-defined]
+        else:
+            parent.append(TextNode(data))
+    
+    def handle_comment(self, data: str) -> None:
+        self._stack[-1].append(RawNode(f"<!--{data}-->"))
+    
+    def handle_decl(self, decl: str) -> None:
+        self._stack[-1].append(RawNode(f"<!{decl}>"))
+    
+    def handle_pi(self, data: str) -> None:
+        self._stack[-1].append(RawNode(f"<?{data}>"))
 
 
 class Document:
@@ -168,125 +1464,976 @@ class Document:
         html_output = doc.render()
     """
     
-    __slots__ = ("_parts", "_tag_stack", "_context_stack")
-    
-    def __init__(self) -> None:
-        """Initialize an empty document."""
+    __slots__ = (
+        "_parts",
+        "_tag_stack",
+        "_context_stack",
+        "_head_entries",
+        "_head_slot",
+        "_xml",
+        "_owner",
+        "_csrf_token",
+        "_csrf_field",
+        "_duplicate_attrs",
+        "_nonce",
+        "_lock",
+        "_blocks",
+    )
+    
+    def __init__(
+        self,
+        xml: bool = False,
+        csrf_token: str | Callable[[], str] | None = None,
+        csrf_field: str = "csrf_token",
+        duplicate_attrs: str = "override",
+        nonce: str | None = None,
+    ) -> None:
+        """Initialize an empty document.
+        
+        Args:
+            xml: Serialize following XML rules, for XHTML, Atom and other XML
+                payloads: boolean attributes are written as ``name="name"``
+                and script/style content is escaped like any other text.
+                Elements are always explicitly closed or self-closed.
+            csrf_token: CSRF token, or a callable returning it, injected as a
+                hidden input at the start of every form() not using GET
+            csrf_field: Name of the hidden CSRF token input
+            duplicate_attrs: What to do when an attribute is supplied twice
+                on a tag, e.g. via a keyword and attr(): "override" keeps
+                the last value, "error" raises ValueError. ``class`` values
+                are always merged with a space.
+            nonce: Content-Security-Policy nonce added as a ``nonce``
+                attribute to every script and style element, unless one is
+                given explicitly
+        
+        Raises:
+            ValueError: If duplicate_attrs is not "override" or "error"
+        """
+        if duplicate_attrs not in ("override", "error"):
+            raise ValueError(
+                f"duplicate_attrs must be 'override' or 'error', got {duplicate_attrs!r}"
+            )
+        self._duplicate_attrs = duplicate_attrs
+        self._nonce = nonce
+        self._lock = threading.Lock()
+        # Open component and cache blocks, innermost last
+        self._blocks: list[_ComponentContext | _CacheContext] = []
+        self._xml = xml
+        self._csrf_token = csrf_token
+        self._csrf_field = csrf_field
         self._parts: list[str] = []
         self._tag_stack: list[str] = []
         self._context_stack: list[_TagContext] = []
+        self._head_entries: dict[str, str] = {}
+        self._head_slot = -1
+        self._owner: int | None = None
+    
+    def _check_thread(self) -> None:
+        """Ensure the calling thread may modify the document.
+        
+        While tags or component/cache blocks are open, the document belongs
+        to the thread that opened the outermost one: interleaving calls from
+        several threads would corrupt the tag stack or the captured content.
+        Once all of them are closed, the document can be rendered or
+        continued from any thread.
+        
+        Raises:
+            RuntimeError: If another thread has tags or blocks open on this
+                document
+        """
+        if (self._context_stack or self._blocks) and self._owner != _get_ident():
+            raise self._thread_error()
+    
+    def _bind_thread(self) -> None:
+        """Bind the document to the calling thread as a block opens.
+        
+        Raises:
+            RuntimeError: If another thread has tags or blocks open on this
+                document
+        """
+        with self._lock:
+            if (self._context_stack or self._blocks) and self._owner != _get_ident():
+                raise self._thread_error()
+            self._owner = _get_ident()
+    
+    def _thread_error(self) -> RuntimeError:
+        """Build the error for a call from a thread not owning the document."""
+        return RuntimeError(
+            "Document is being built by another thread: a Document can only be "
+            "used from one thread while it has open tags or blocks"
+        )
+    
+    def _leave_block(self, block: _ComponentContext | _CacheContext) -> None:
+        """Pop a component or cache block, unless clear() already dropped it."""
+        if self._blocks and self._blocks[-1] is block:
+            self._blocks.pop()
+    
+    def _truncate(self, length: int) -> None:
+        """Drop the output parts from index length on.
+        
+        The head entries slot is forgotten if it was among them, so render()
+        doesn't write head entries over unrelated content.
+        """
+        del self._parts[length:]
+        if self._head_slot >= length:
+            self._head_slot = -1
+    
+    def _open_path(self) -> str:
+        """Describe the open elements, e.g. "html > body > div#main > ul"."""
+        steps = []
+        for context in self._context_stack:
+            element_id = context._attrs.get("id")
+            if element_id is None:
+                steps.append(context._tag_name)
+            else:
+                steps.append(f"{context._tag_name}#{element_id}")
+        return " > ".join(steps)
+    
+    def _unclosed_error(self, message: str) -> RuntimeError:
+        """Build the error for unclosed tags, locating the innermost one.
+        
+        Operation indexes are positions in the document's output buffer,
+        where every opening tag, closing tag, text or raw insertion takes
+        one entry, in build order.
+        """
+        return RuntimeError(
+            f"{message}: {self._open_path()} "
+            f"(innermost opened at operation {self._context_stack[-1]._slot})"
+        )
     
     def tag(self, tag_name: str, **attrs: Any) -> _TagContext:
         """Create a tag context manager.
         
+        Underscores in the tag name are converted to hyphens, so custom
+        elements can be written as Python-friendly names (``my_counter`` ->
+        ``<my-counter>``).
+        
+        Args:
+            tag_name: The HTML tag name (e.g., "div", "p", "img")
+            **attrs: HTML attributes as keyword arguments
+        
+        Returns:
+            A context manager that handles opening and closing the tag
+        
+        Example:
+            with doc.tag("div", class_="container", data_value="123"):
+                doc.text("Content")
+        """
+        info = _TAG_INFO_CACHE.get(tag_name)
+        if info is None:
+            info = _tag_info(tag_name)
+        
+        if self._nonce is not None and info[0].lower() in _RAW_TEXT_ELEMENTS:
+            attrs.setdefault("nonce", self._nonce)
+        
+        # Create the tag context (opening tag is finalized on exit)
+        if info[0] == "head":
+            return _HeadContext(self, "head", attrs)
+        return _TagContext(self, info[0], attrs, info[1])
+    
+    def component(self, name: str, **props: Any) -> _ComponentContext:
+        """Invoke a registered component, with the block content as children.
+        
+        Args:
+            name: The name the component was registered with
+            **props: Keyword arguments passed to the component
+        
+        Raises:
+            ValueError: If no component is registered under that name
+            RuntimeError: On exit, if head() was used inside the block; the
+                component function itself may write the head
+        
+        Example:
+            with doc.component("card", title="Hello"):
+                doc.text("Card body")
+        """
+        func = _COMPONENTS.get(name)
+        if func is None:
+            raise ValueError(f"Unknown component '{name}'")
+        return _ComponentContext(self, func, props)
+    
+    def cache(self, key: str, ttl: float | None = None) -> _CacheContext:
+        """Cache the content written inside the block under a string key.
+        
+        Python cannot skip the body of a ``with`` block, so the context
+        manager yields True on a cache hit (the cached markup has already
+        been spliced in) and the body should be guarded accordingly. The
+        cache is an LRU shared by all documents, with separate entries for
+        HTML and XML documents. Head entries declared inside a cached block
+        are not replayed on hits.
+        
+        Args:
+            key: The cache key, which should include any data version
+            ttl: Time-to-live of the cached fragment in seconds, or None
+                to keep it until evicted
+        
+        Raises:
+            RuntimeError: If head() or a form carrying a CSRF token is used
+                inside the block, since they can't be replayed to other
+                documents
+        
+        Example:
+            with doc.cache("sidebar-v2", ttl=60) as hit:
+                if not hit:
+                    render_sidebar(doc)
+        """
+        return _CacheContext(self, key, ttl)
+    
+    def custom(self, tag_name: str, **attrs: Any) -> _TagContext:
+        """Create a custom element (web component) tag context manager.
+        
+        Args:
+            tag_name: The custom element name, which must start with a
+                lowercase ASCII letter and contain a hyphen (underscores are
+                converted to hyphens)
+            **attrs: HTML attributes as keyword arguments
+        
+        Raises:
+            ValueError: If the name is not a valid custom element name
+        
+        Example:
+            with doc.custom("x-widget", mode="compact"):
+                doc.text("Loading...")
+        """
+        name = tag_name.replace("_", "-")
+        if not _CUSTOM_ELEMENT_NAME_PATTERN.match(name):
+            raise ValueError(
+                f"Invalid custom element name '{tag_name}': it must start with a "
+                "lowercase ASCII letter and contain a hyphen"
+            )
+        return _TagContext(self, name, attrs)
+    
+    def text(self, content: Any) -> None:
+        """Add text content to the document.
+        
+        Objects implementing the ``__html__()`` protocol (e.g. markupsafe's
+        ``Markup``) are already safe HTML, so their ``__html__()`` result is
+        inserted as-is instead of escaping their ``str()``.
+        
+        Inside ``<script>`` and ``<style>`` elements, the content is JS/CSS
+        rather than HTML: it is not entity-escaped, but sequences that would
+        terminate the element early (``</script>``) are neutralized.
+        
+        Args:
+            content: The text content to add (will be escaped for safety)
+        
+        Example:
+            doc.text("Hello & welcome!")  # Becomes "Hello &amp; welcome!"
+            doc.text(Markup("<b>bold</b>"))  # Inserted unescaped
+        """
+        if content is not None:
+            tag_stack = self._tag_stack
+            if tag_stack:
+                if self._owner != _get_ident():
+                    self._check_thread()
+                if self._context_stack[-1]._self_closing:
+                    raise self._context_stack[-1]._children_error(len(self._parts))
+                if tag_stack[-1] in _RAW_TEXT_NAMES and not self._xml:
+                    self._parts.append(_guard_raw_text(content))
+                    return
+            elif self._blocks and self._owner != _get_ident():
+                raise self._thread_error()
+            if type(content) is str:
+                if _TEXT_SPECIAL_CHARS.search(content):
+                    content = html.escape(content, quote=False)
+                self._parts.append(content)
+                return
+            html_method = getattr(content, "__html__", None)
+            if html_method is not None:
+                self._parts.append(str(html_method()))
+                return
+            self._parts.append(_escape_text(content))
+    
+    def raw(self, content: Any) -> None:
+        """Add raw HTML content to the document without escaping.
+        
+        WARNING: This method does not escape content. Only use with trusted input.
+        
+        Args:
+            content: The raw HTML content to add
+        
+        Example:
+            doc.raw("<em>Already formatted</em>")
+        """
+        if content is not None:
+            self._check_thread()
+            self._parts.append(str(content))
+    
+    def raw_template(self, rendered: Any) -> None:
+        """Add the output of a template engine such as Jinja2.
+        
+        Template output is already escaped by the engine, so it is inserted
+        as-is. ``Markup`` objects are unwrapped through ``__html__()``.
+        
+        WARNING: Only use with output of templates rendered with autoescaping
+        or otherwise trusted.
+        
+        Args:
+            rendered: The rendered template string
+        
+        Example:
+            doc.raw_template(env.get_template("card.html").render(user=user))
+        """
+        if rendered is not None:
+            html_method = getattr(rendered, "__html__", None)
+            self._check_thread()
+            self._parts.append(str(html_method() if html_method is not None else rendered))
+    
+    def include(self, content: Document | Fragment) -> None:
+        """Splice a previously built Document or Fragment at the current position.
+        
+        The content is already HTML and is not escaped again, which enables
+        partials and layout composition. Managed head entries declared by an
+        included partial (title, meta, link_css) are merged into this
+        document, replacing entries with the same key.
+        
+        Args:
+            content: A Document with no unclosed tags, or a Fragment
+        
+        Raises:
+            RuntimeError: If the included document has unclosed tags
+            TypeError: If content is neither a Document nor a Fragment
+        
+        Example:
+            sidebar = Document()
+            with sidebar.aside():
+                sidebar.text("Links")
+            with doc.main():
+                doc.include(sidebar)
+        """
+        self._check_thread()
+        if isinstance(content, Fragment):
+            self._parts.append(content._markup)
+        elif isinstance(content, Document):
+            if content is self:
+                raise RuntimeError("Cannot include a document into itself")
+            if content._head_slot >= 0:
+                # A complete page: its head entries belong to its own <head>
+                self._parts.extend(content._finalize())
+            else:
+                if content._tag_stack:
+                    raise content._unclosed_error("Cannot include a document with unclosed tags")
+                self._parts.extend(content._parts)
+                self._head_entries.update(content._head_entries)
+        else:
+            raise TypeError(
+                f"include() expects a Document or Fragment, got {type(content).__name__}"
+            )
+    
+    def safe_raw(
+        self,
+        content: Any,
+        tags: set[str] | None = None,
+        attributes: dict[str, set[str]] | None = None,
+    ) -> None:
+        """Add untrusted HTML content after running it through a sanitizer.
+        
+        Unlike raw(), the content is cleaned with nh3 (Python bindings for
+        the ammonia sanitizer), so user-generated HTML can be embedded
+        without opening an XSS hole. Requires the optional ``nh3`` package.
+        
+        Args:
+            content: The untrusted HTML content to add
+            tags: Allowed tag names (defaults to ammonia's safe allowlist)
+            attributes: Allowed attributes per tag name, with "*" applying
+                to all tags (defaults to ammonia's safe allowlist)
+        
+        Raises:
+            ImportError: If nh3 is not installed
+        
+        Example:
+            doc.safe_raw(comment_html, tags={"p", "a", "em"}, attributes={"a": {"href"}})
+        """
+        if content is None:
+            return
+        try:
+            import nh3
+        except ImportError as e:
+            raise ImportError(
+                "safe_raw() requires the nh3 package: install tagflow-reimplementation[sanitize]"
+            ) from e
+        options: dict[str, Any] = {}
+        if tags is not None:
+            options["tags"] = tags
+        if attributes is not None:
+            options["attributes"] = attributes
+        self._check_thread()
+        self._parts.append(nh3.clean(str(content), **options))
+    
+    def markdown(self, text: Any, sanitize: bool = False) -> None:
+        """Render CommonMark text to HTML and add it to the document.
+        
+        Parsing uses markdown-it-py with its strict CommonMark preset.
+        Requires the optional ``markdown-it-py`` package.
+        
+        Args:
+            text: The Markdown source
+            sanitize: Run the resulting HTML through safe_raw(), for
+                untrusted Markdown which may contain inline HTML
+        
+        Raises:
+            ImportError: If markdown-it-py (or nh3 when sanitizing) is not
+                installed
+        
+        Example:
+            doc.markdown(post.body, sanitize=True)
+        """
+        if text is None:
+            return
+        try:
+            from markdown_it import MarkdownIt
+        except ImportError as e:
+            raise ImportError(
+                "markdown() requires the markdown-it-py package: install tagflow-reimplementation[markdown]"
+            ) from e
+        html = MarkdownIt("commonmark").render(str(text))
+        if sanitize:
+            self.safe_raw(html)
+        else:
+            self._check_thread()
+            self._parts.append(html)
+    
+    def attr(self, name: str, value: Any) -> None:
+        """Add an attribute to the current tag.
+        
+        This method allows dynamic addition of attributes to the currently
+        open tag context. This is very useful for conditional logic. Since
+        the opening tag is finalized when the context exits, attr() can be
+        called at any point inside the block, even after content was added.
+        
+        Args:
+            name: The attribute name (will be converted like other attributes)
+            value: The attribute value
+            
+        Raises:
+            RuntimeError: If there is no current tag context, or it was
+                opened outside the innermost component block
+            ValueError: If the attribute is already set on the tag and the
+                document was created with ``duplicate_attrs="error"``
+            
+        Example:
+            with doc.div() as div_tag:
+                if user_is_admin:
+                    doc.attr("class", "admin-panel")
+                    doc.attr("data-role", "administrator")
+                doc.text("Content")
+        """
+        self._check_thread()
+        if not self._context_stack:
+            raise RuntimeError("No current tag context. attr() can only be called within a tag context.")
+        blocks = self._blocks
+        if blocks and type(blocks[-1]) is _ComponentContext and blocks[-1]._depth == len(self._context_stack):
+            raise RuntimeError(
+                "attr() cannot be called directly inside a component block: "
+                "it would set the attribute on the tag enclosing the component"
+            )
+        
+        self._context_stack[-1].add_attr(name, value)
+    
+    def _finalize(self) -> list[str]:
+        """Check the document is complete and return its parts ready for output.
+        
+        Raises:
+            RuntimeError: If there are unclosed tags or managed head entries
+                without a head element
+        """
+        if self._tag_stack:
+            raise self._unclosed_error("Unclosed tags")
+        
+        if self._head_entries:
+            if self._head_slot < 0:
+                raise RuntimeError(
+                    "Managed head entries (title, meta, link_css) require a head() element"
+                )
+            self._parts[self._head_slot] = "".join(self._head_entries.values())
+        
+        return self._parts
+    
+    def render(self, pretty: bool = False, indent: int = 2, minify: bool = False) -> str:
+        """Render the document to an HTML string.
+        
+        Args:
+            pretty: If True, produce indented, newline-separated HTML for
+                debugging and snapshot tests instead of compact output
+            indent: Number of spaces per nesting level when pretty is True
+            minify: If True, collapse insignificant whitespace (e.g. from
+                raw templates or text) while preserving the content of pre,
+                textarea, script and style elements
+        
+        Returns:
+            The complete HTML document as a string
+        
+        Raises:
+            RuntimeError: If there are unclosed tags
+            ValueError: If both pretty and minify are requested
+        """
+        if pretty and minify:
+            raise ValueError("pretty and minify are mutually exclusive")
+        output = "".join(self._finalize())
+        if pretty:
+            return _prettify(output, indent)
+        if minify:
+            return _minify(output)
+        return output
+    
+    def to_tree(self) -> Element:
+        """Build an element tree from the document for post-build mutation.
+        
+        The document itself stays a flat string buffer for speed; the tree
+        is parsed from its rendered output on demand. It supports setting
+        attributes after the fact, inserting or removing children, and
+        re-serializing with render(), and is the basis for querying.
+        
+        Returns:
+            A root Element with an empty tag whose children are the
+            top-level nodes of the document
+        
+        Raises:
+            RuntimeError: If there are unclosed tags
+        """
+        builder = _TreeBuilder()
+        builder.feed(self.render())
+        builder.close()
+        return builder.root
+    
+    def find(self, selector: str) -> list[Element]:
+        """Query the document with a CSS selector.
+        
+        Returns Element handles exposing tag, attrs and text, which is handy
+        in unit tests instead of matching the rendered string. The element
+        tree is built on each call; use to_tree().find() for repeated queries.
+        
+        Raises:
+            RuntimeError: If there are unclosed tags
+            ValueError: If the selector is invalid or uses unsupported syntax
+        
+        Example:
+            links = doc.find("div.card > a[href]")
+            assert [link.get("href") for link in links] == ["/a", "/b"]
+        """
+        return self.to_tree().find(selector)
+    
+    def find_one(self, selector: str) -> Element | None:
+        """Return the first element matching a CSS selector, or None."""
+        return self.to_tree().find_one(selector)
+    
+    @staticmethod
+    def diff(old: Document | Element, new: Document | Element) -> list[dict[str, Any]]:
+        """Compute DOM patch operations turning old into new.
+        
+        Operations are keyed by element ids, so they can drive morphdom,
+        idiomorph or a small client script on live-updating pages. Each is a
+        JSON-serializable dict with an ``op`` and a ``target`` id:
+        
+        - ``{"op": "set_attr", "target": id, "name": ..., "value": ...}``
+          (a None value is a bare boolean attribute)
+        - ``{"op": "remove_attr", "target": id, "name": ...}``
+        - ``{"op": "remove", "target": id}`` removes the element
+        - ``{"op": "replace", "target": id, "html": ...}`` replaces the
+          element with new markup
+        
+        Changes that cannot be targeted through an id replace the closest
+        enclosing element with an id, or the whole document (target None).
+        
+        Args:
+            old: The previously sent document (or element tree)
+            new: The updated document (or element tree)
+        
+        Returns:
+            The list of patch operations, empty if nothing changed
+        
+        Example:
+            patches = Document.diff(previous_doc, doc)
+            await websocket.send_json(patches)
+        """
+        old_tree = old.to_tree() if isinstance(old, Document) else old
+        new_tree = new.to_tree() if isinstance(new, Document) else new
+        patches = _diff_element(old_tree, new_tree)
+        if patches is None:
+            return [{"op": "replace", "target": None, "html": new_tree.render()}]
+        return patches
+    
+    def render_bytes(self) -> bytes:
+        """Render the document directly to UTF-8 encoded bytes.
+        
+        Convenient for ASGI responses, which expect bytes rather than str.
+        
+        Raises:
+            RuntimeError: If there are unclosed tags
+        
+        Example:
+            return Response(doc.render_bytes(), media_type="text/html")
+        """
+        return "".join(self._finalize()).encode("utf-8")
+    
+    def fragment(self) -> Fragment:
+        """Render the document to a Fragment that can be included elsewhere.
+        
+        Raises:
+            RuntimeError: If there are unclosed tags
+        """
+        return Fragment(self.render())
+    
+    def stream(self, chunk_size: int = 16384) -> Iterator[bytes]:
+        """Render the document incrementally as UTF-8 encoded chunks.
+        
+        Chunks are produced from the internal buffer as the iterator is
+        consumed, so large pages can be sent with chunked transfer encoding
+        (e.g. via a Starlette StreamingResponse) without materializing one
+        giant string. Every chunk but the last is exactly chunk_size bytes.
+        
+        Args:
+            chunk_size: Size of the produced chunks in bytes
+        
+        Raises:
+            ValueError: If chunk_size is not positive
+            RuntimeError: If there are unclosed tags
+        
+        Example:
+            return StreamingResponse(doc.stream(), media_type="text/html")
+        """
+        if chunk_size <= 0:
+            raise ValueError("chunk_size must be positive")
+        parts = self._finalize()
+        return self._iter_chunks(parts, chunk_size)
+    
+    def write_to(self, fp: Any, chunk_size: int = 16384) -> None:
+        """Write the rendered document into a file-like object in chunks.
+        
+        Text streams (io.TextIOBase subclasses such as io.StringIO or files
+        opened in text mode) receive str chunks; any other file-like object
+        (io.BufferedWriter, io.BytesIO, ...) receives UTF-8 encoded bytes.
+        The document is never joined into a single contiguous string.
+        
         Args:
-            tag_name: The HTML tag name (e.g., "div", "p", "img")
-            **attrs: HTML attributes as keyword arguments
+            fp: The file-like object to write into
+            chunk_size: Approximate size of each write, in characters for
+                text streams and in bytes otherwise
         
-        Returns:
-            A context manager that handles opening and closing the tag
+        Raises:
+            ValueError: If chunk_size is not positive
+            RuntimeError: If there are unclosed tags
         
         Example:
-            with doc.tag("div", class_="container", data_value="123"):
-                doc.text("Content")
+            with open("report.html", "wb") as fp:
+                doc.write_to(fp)
         """
-        # Ensure the current tag (if any) is opened before creating a nested tag
-        if self._context_stack:
-            self._context_stack[-1]._ensure_opened()
+        if chunk_size <= 0:
+            raise ValueError("chunk_size must be positive")
+        parts = self._finalize()
+        if isinstance(fp, io.TextIOBase):
+            batch: list[str] = []
+            size = 0
+            for part in parts:
+                batch.append(part)
+                size += len(part)
+                if size >= chunk_size:
+                    fp.write("".join(batch))
+                    batch.clear()
+                    size = 0
+            if batch:
+                fp.write("".join(batch))
+        else:
+            for chunk in self._iter_chunks(parts, chunk_size):
+                fp.write(chunk)
+    
+    @staticmethod
+    def _iter_chunks(parts: list[str], chunk_size: int) -> Iterator[bytes]:
+        buffer = bytearray()
+        for part in parts:
+            buffer += part.encode("utf-8")
+            while len(buffer) >= chunk_size:
+                yield bytes(buffer[:chunk_size])
+                del buffer[:chunk_size]
+        if buffer:
+            yield bytes(buffer)
+    
+    def clear(self) -> None:
+        """Clear the document content, allowing reuse of the same Document object."""
+        self._check_thread()
+        self._parts.clear()
+        self._tag_stack.clear()
+        self._context_stack.clear()
+        self._blocks.clear()
+        self._head_entries.clear()
+        self._head_slot = -1
+    
+    def snapshot(self) -> _Snapshot:
+        """Capture the current state of the document for rollback().
         
-        # Check for self-closing tags
-        self_closing = tag_name.lower() in {
-            "area", "base", "br", "col", "embed", "hr", "img", "input",
-            "link", "meta", "param", "source", "track", "wbr"
-        }
+        The snapshot records the output buffer position, the open tags with
+        their pending attributes and the managed head entries; it doesn't
+        copy the content itself.
         
-        # Create the tag context (opening tag is written lazily)
-        context = _TagContext(self, tag_name, attrs, self_closing)
-        return context
+        Example:
+            token = doc.snapshot()
+            try:
+                render_recommendations(doc, user)
+            except LookupError:
+                doc.rollback(token)  # Discard the half-rendered section
+        """
+        self._check_thread()
+        return _Snapshot(self)
     
-    def text(self, content: Any) -> None:
-        """Add text content to the document.
+    def rollback(self, token: _Snapshot) -> None:
+        """Restore the document to a state captured by snapshot().
         
-        Args:
-            content: The text content to add (will be escaped for safety)
+        Content written and tags opened since the snapshot are discarded,
+        and attributes of the open tags and managed head entries are
+        restored. Call it outside of any
+        ``with`` block opened after the snapshot: such a block raises
+        RuntimeError when it exits.
+        
+        Raises:
+            ValueError: If the snapshot belongs to another document, or is
+                no longer valid because the document was cleared, rolled
+                back further, or a tag open at snapshot time was closed
+        """
+        self._check_thread()
+        if token._document is not self:
+            raise ValueError("Cannot roll back to a snapshot of another document")
+        depth = len(token._contexts)
+        if (
+            len(self._parts) < token._length
+            or tuple(self._context_stack[:depth]) != token._contexts
+        ):
+            raise ValueError("Snapshot is no longer valid for this document")
+        del self._parts[token._length:]
+        del self._context_stack[depth:]
+        del self._tag_stack[depth:]
+        for context, attrs in zip(token._contexts, token._attrs):
+            context._attrs = dict(attrs)
+        self._head_entries = dict(token._head_entries)
+        self._head_slot = token._head_slot
+    
+    def doctype(self, declaration: str = "html") -> None:
+        """Add a document type declaration.
         
         Example:
-            doc.text("Hello & welcome!")  # Becomes "Hello &amp; welcome!"
+            doc.doctype()  # <!DOCTYPE html>
         """
-        if content is not None:
-            # Ensure current tag is opened before adding content
-            if self._context_stack:
-                self._context_stack[-1]._ensure_opened()
-            self._parts.append(_escape_text(content))
+        self._check_thread()
+        self._parts.append(f"<!DOCTYPE {declaration}>")
     
-    def raw(self, content: Any) -> None:
-        """Add raw HTML content to the document without escaping.
+    def xml_declaration(
+        self, version: str = "1.0", encoding: str | None = "UTF-8", standalone: bool | None = None
+    ) -> None:
+        """Add an XML declaration, typically first in an XML document.
         
-        WARNING: This method does not escape content. Only use with trusted input.
+        Example:
+            doc.xml_declaration()  # <?xml version="1.0" encoding="UTF-8"?>
+        """
+        declaration = f'<?xml version="{_escape_attr_value(version)}"'
+        if encoding is not None:
+            declaration += f' encoding="{_escape_attr_value(encoding)}"'
+        if standalone is not None:
+            declaration += f' standalone="{"yes" if standalone else "no"}"'
+        self._check_thread()
+        self._parts.append(declaration + "?>")
+    
+    # Managed head entries, serialized inside <head> at render time
+    
+    def title(self, content: Any) -> None:
+        """Set the document title, replacing any previously set title.
         
         Args:
-            content: The raw HTML content to add
+            content: The title text (will be escaped for safety)
+        """
+        self._check_thread()
+        self._head_entries["title"] = f"<title>{_escape_text(content)}</title>"
+    
+    def meta(self, **attrs: Any) -> None:
+        """Add a meta entry to the document head.
+        
+        Entries are deduplicated by their charset, name, property or
+        http-equiv attribute; a later call replaces the earlier entry.
         
         Example:
-            doc.raw("<em>Already formatted</em>")
+            doc.meta(charset="utf-8")
+            doc.meta(name="viewport", content="width=device-width")
         """
-        if content is not None:
-            # Ensure current tag is opened before adding content
-            if self._context_stack:
-                self._context_stack[-1]._ensure_opened()
-            self._parts.append(str(content))
+        self._check_thread()
+        converted = {_convert_attr_name(k): v for k, v in attrs.items()}
+        for key_attr in ("charset", "name", "property", "http-equiv"):
+            if key_attr in converted:
+                key = f"meta:{key_attr}" if key_attr == "charset" else f"meta:{key_attr}={converted[key_attr]}"
+                break
+        else:
+            key = f"meta:{_format_attrs(converted)}"
+        self._head_entries[key] = f"<meta{_format_attrs(converted, self._xml)} />"
     
-    def attr(self, name: str, value: Any) -> None:
-        """Add an attribute to the current tag.
+    def link_css(self, href: str, **attrs: Any) -> None:
+        """Add a stylesheet link to the document head, deduplicated by href.
         
-        This method allows dynamic addition of attributes to the currently
-        open tag context. This is very useful for conditional logic.
+        Example:
+            doc.link_css("/static/app.css")
+        """
+        self._check_thread()
+        self._head_entries[f"link:{href}"] = (
+            f'<link rel="stylesheet" href="{_escape_attr_value(href)}"{_format_attrs(attrs, self._xml)} />'
+        )
+    
+    def __str__(self) -> str:
+        """Return the rendered HTML when converting to string."""
+        return self.render()
+    
+    # Form helpers
+    
+    def input_(self, type: str = "text", name: str | None = None, value: Any = None, **attrs: Any) -> None:
+        """Write an input element immediately.
+        
+        Unlike the input() shortcut, this doesn't need a context manager.
+        
+        Example:
+            doc.input_("email", "email", user.email, required=True)
+        """
+        input_attrs: dict[str, Any] = {"type": type, "name": name, "value": value}
+        input_attrs.update(attrs)
+        with _TagContext(self, "input", input_attrs, True):
+            pass
+    
+    def select_(
+        self,
+        name: str,
+        options: Any,
+        selected: Any = None,
+        **attrs: Any,
+    ) -> None:
+        """Write a complete select element with its options.
         
         Args:
-            name: The attribute name (will be converted like other attributes)
-            value: The attribute value
-            
+            name: The select name
+            options: A dict of value -> label, or an iterable of values or
+                (value, label) pairs given as tuples or lists
+            selected: The selected value, or a list/set of values for
+                ``multiple`` selects; compared as strings
+            **attrs: Additional attributes of the select element
+        
         Raises:
-            RuntimeError: If there is no current tag context or if the tag has already been opened
-            
+            TypeError: If an option pair doesn't have exactly two items
+        
         Example:
-            with doc.div() as div_tag:
-                if user_is_admin:
-                    doc.attr("class", "admin-panel")
-                    doc.attr("data-role", "administrator")
-                doc.text("Content")
+            doc.select_("country", {"fr": "France", "de": "Germany"}, selected="fr")
         """
-        if not self._context_stack:
-            raise RuntimeError("No current tag context. attr() can only be called within a tag context.")
+        if selected is None:
+            selected_values: set[str] = set()
+        elif isinstance(selected, (list, tuple, set, frozenset)):
+            selected_values = {str(value) for value in selected}
+        else:
+            selected_values = {str(selected)}
         
-        self._context_stack[-1].add_attr(name, value)
+        items = options.items() if isinstance(options, dict) else options
+        with self.tag("select", name=name, **attrs):
+            for item in items:
+                if isinstance(item, (tuple, list)):
+                    if len(item) != 2:
+                        raise TypeError(f"Option pairs must be (value, label), got {item!r}")
+                    value, label = item
+                else:
+                    value = label = item
+                with self.tag("option", value=value, selected=str(value) in selected_values):
+                    self.text(label)
+    
+    def field(
+        self,
+        label: Any,
+        name: str,
+        type: str = "text",
+        value: Any = None,
+        id: str | None = None,
+        **attrs: Any,
+    ) -> None:
+        """Write a label and its paired input.
+        
+        The label's ``for`` points at the input's id, which defaults to the
+        field name.
+        
+        Example:
+            doc.field("Email", "email", type="email", required=True)
+            # <label for="email">Email</label><input type="email" name="email" id="email" required />
+        """
+        field_id = id if id is not None else name
+        with self.tag("label", for_=field_id):
+            self.text(label)
+        self.input_(type, name, value, id=field_id, **attrs)
     
-    def render(self) -> str:
-        """Render the document to an HTML string.
+    # Table helper
+    
+    def table_from(self, rows: Any, columns: Any, **attrs: Any) -> None:
+        """Write a complete table with thead and tbody from records.
         
-        Returns:
-            The complete HTML document as a string
+        Args:
+            rows: An iterable of dicts or objects, one per table row
+            columns: Column specs, each either a string key used as its
+                own header, or a tuple ``(header, key)`` or
+                ``(header, key, align)``. The key is a dict key or attribute
+                name, or a callable receiving the row; align ("left",
+                "center" or "right") sets ``text-align`` on the column's
+                cells.
+            **attrs: Additional attributes of the table element
         
         Raises:
-            RuntimeError: If there are unclosed tags
+            TypeError: If a column spec is neither a string nor a tuple of
+                two or three items
+        
+        Cell values are escaped like any other text; ``None`` renders as an
+        empty cell.
+        
+        Example:
+            doc.table_from(users, [
+                ("Name", "name"),
+                ("Posts", lambda user: len(user.posts), "right"),
+            ])
         """
-        if self._tag_stack:
-            unclosed = ", ".join(self._tag_stack)
-            raise RuntimeError(f"Unclosed tags: {unclosed}")
+        specs = []
+        for column in columns:
+            if isinstance(column, tuple) and len(column) in (2, 3):
+                header, key, align = column if len(column) == 3 else (*column, None)
+            elif isinstance(column, str):
+                header, key, align = column, column, None
+            else:
+                raise TypeError(
+                    f"Column specs must be a key or a (header, key[, align]) tuple, got {column!r}"
+                )
+            style = {"text_align": align} if align is not None else None
+            specs.append((header, key, style))
         
-        return "".join(self._parts)
+        with self.tag("table", **attrs):
+            with self.tag("thead"):
+                with self.tag("tr"):
+                    for header, _, style in specs:
+                        with self.tag("th", style=style):
+                            self.text(header)
+            with self.tag("tbody"):
+                for row in rows:
+                    with self.tag("tr"):
+                        for _, key, style in specs:
+                            if callable(key):
+                                value = key(row)
+                            elif isinstance(row, dict):
+                                value = row.get(key)
+                            else:
+                                value = getattr(row, key, None)
+                            with self.tag("td", style=style):
+                                if value is not None:
+                                    self.text(value)
     
-    def clear(self) -> None:
-        """Clear the document content, allowing reuse of the same Document object."""
-        self._parts.clear()
-        self._tag_stack.clear()
-        self._context_stack.clear()
+    # Shortcut methods for common HTML tags
     
-    def __str__(self) -> str:
-        """Return the rendered HTML when converting to string."""
-        return self.render()
+    def html(self, **attrs: Any) -> _TagContext:
+        """Create an html tag. Shortcut for tag('html', **attrs)."""
+        return self.tag("html", **attrs)
     
-    # Shortcut methods for common HTML tags
+    def head(self, **attrs: Any) -> _TagContext:
+        """Create a head tag. Shortcut for tag('head', **attrs).
+        
+        Managed head entries (title, meta, link_css) are serialized inside it.
+        """
+        return self.tag("head", **attrs)
+    
+    def body(self, **attrs: Any) -> _TagContext:
+        """Create a body tag. Shortcut for tag('body', **attrs)."""
+        return self.tag("body", **attrs)
     
     def div(self, **attrs: Any) -> _TagContext:
         """Create a div tag. Shortcut for tag('div', **attrs)."""
@@ -332,9 +2479,15 @@ class Document:
         """Create a button tag. Shortcut for tag('button', **attrs)."""
         return self.tag("button", **attrs)
     
-    def form(self, **attrs: Any) -> _TagContext:
-        """Create a form tag. Shortcut for tag('form', **attrs)."""
-        return self.tag("form", **attrs)
+    def form(self, action: str | None = None, method: str | None = None, **attrs: Any) -> _TagContext:
+        """Create a form tag. Shortcut for tag('form', action=..., method=..., **attrs).
+        
+        If the document has a CSRF token and the method is not GET or dialog,
+        a hidden token input is injected at the start of the form.
+        """
+        form_attrs: dict[str, Any] = {"action": action, "method": method}
+        form_attrs.update(attrs)
+        return _FormContext(self, "form", form_attrs)
     
     def input(self, **attrs: Any) -> _TagContext:
         """Create an input tag. Shortcut for tag('input', **attrs)."""
@@ -459,6 +2612,525 @@ class Document:
         """
         with self.tag("hr", **attrs):
             pass
+    
+    # Shortcut methods for the remaining HTML5 elements
+    
+    def style(self, **attrs: Any) -> _TagContext:
+        """Create a style tag. Shortcut for tag('style', **attrs)."""
+        return self.tag("style", **attrs)
+    
+    def address(self, **attrs: Any) -> _TagContext:
+        """Create an address tag. Shortcut for tag('address', **attrs)."""
+        return self.tag("address", **attrs)
+    
+    def hgroup(self, **attrs: Any) -> _TagContext:
+        """Create a hgroup tag. Shortcut for tag('hgroup', **attrs)."""
+        return self.tag("hgroup", **attrs)
+    
+    def blockquote(self, **attrs: Any) -> _TagContext:
+        """Create a blockquote tag. Shortcut for tag('blockquote', **attrs)."""
+        return self.tag("blockquote", **attrs)
+    
+    def menu(self, **attrs: Any) -> _TagContext:
+        """Create a menu tag. Shortcut for tag('menu', **attrs)."""
+        return self.tag("menu", **attrs)
+    
+    def dl(self, **attrs: Any) -> _TagContext:
+        """Create a dl tag. Shortcut for tag('dl', **attrs)."""
+        return self.tag("dl", **attrs)
+    
+    def dt(self, **attrs: Any) -> _TagContext:
+        """Create a dt tag. Shortcut for tag('dt', **attrs)."""
+        return self.tag("dt", **attrs)
+    
+    def dd(self, **attrs: Any) -> _TagContext:
+        """Create a dd tag. Shortcut for tag('dd', **attrs)."""
+        return self.tag("dd", **attrs)
+    
+    def figure(self, **attrs: Any) -> _TagContext:
+        """Create a figure tag. Shortcut for tag('figure', **attrs)."""
+        return self.tag("figure", **attrs)
+    
+    def figcaption(self, **attrs: Any) -> _TagContext:
+        """Create a figcaption tag. Shortcut for tag('figcaption', **attrs)."""
+        return self.tag("figcaption", **attrs)
+    
+    def search(self, **attrs: Any) -> _TagContext:
+        """Create a search tag. Shortcut for tag('search', **attrs)."""
+        return self.tag("search", **attrs)
+    
+    def small(self, **attrs: Any) -> _TagContext:
+        """Create a small tag. Shortcut for tag('small', **attrs)."""
+        return self.tag("small", **attrs)
+    
+    def s(self, **attrs: Any) -> _TagContext:
+        """Create a s tag. Shortcut for tag('s', **attrs)."""
+        return self.tag("s", **attrs)
+    
+    def cite(self, **attrs: Any) -> _TagContext:
+        """Create a cite tag. Shortcut for tag('cite', **attrs)."""
+        return self.tag("cite", **attrs)
+    
+    def q(self, **attrs: Any) -> _TagContext:
+        """Create a q tag. Shortcut for tag('q', **attrs)."""
+        return self.tag("q", **attrs)
+    
+    def dfn(self, **attrs: Any) -> _TagContext:
+        """Create a dfn tag. Shortcut for tag('dfn', **attrs)."""
+        return self.tag("dfn", **attrs)
+    
+    def abbr(self, **attrs: Any) -> _TagContext:
+        """Create an abbr tag. Shortcut for tag('abbr', **attrs)."""
+        return self.tag("abbr", **attrs)
+    
+    def ruby(self, **attrs: Any) -> _TagContext:
+        """Create a ruby tag. Shortcut for tag('ruby', **attrs)."""
+        return self.tag("ruby", **attrs)
+    
+    def rt(self, **attrs: Any) -> _TagContext:
+        """Create a rt tag. Shortcut for tag('rt', **attrs)."""
+        return self.tag("rt", **attrs)
+    
+    def rp(self, **attrs: Any) -> _TagContext:
+        """Create a rp tag. Shortcut for tag('rp', **attrs)."""
+        return self.tag("rp", **attrs)
+    
+    def data(self, **attrs: Any) -> _TagContext:
+        """Create a data tag. Shortcut for tag('data', **attrs)."""
+        return self.tag("data", **attrs)
+    
+    def time(self, **attrs: Any) -> _TagContext:
+        """Create a time tag. Shortcut for tag('time', **attrs)."""
+        return self.tag("time", **attrs)
+    
+    def var(self, **attrs: Any) -> _TagContext:
+        """Create a var tag. Shortcut for tag('var', **attrs)."""
+        return self.tag("var", **attrs)
+    
+    def samp(self, **attrs: Any) -> _TagContext:
+        """Create a samp tag. Shortcut for tag('samp', **attrs)."""
+        return self.tag("samp", **attrs)
+    
+    def kbd(self, **attrs: Any) -> _TagContext:
+        """Create a kbd tag. Shortcut for tag('kbd', **attrs)."""
+        return self.tag("kbd", **attrs)
+    
+    def sub(self, **attrs: Any) -> _TagContext:
+        """Create a sub tag. Shortcut for tag('sub', **attrs)."""
+        return self.tag("sub", **attrs)
+    
+    def sup(self, **attrs: Any) -> _TagContext:
+        """Create a sup tag. Shortcut for tag('sup', **attrs)."""
+        return self.tag("sup", **attrs)
+    
+    def i(self, **attrs: Any) -> _TagContext:
+        """Create an i tag. Shortcut for tag('i', **attrs)."""
+        return self.tag("i", **attrs)
+    
+    def b(self, **attrs: Any) -> _TagContext:
+        """Create a b tag. Shortcut for tag('b', **attrs)."""
+        return self.tag("b", **attrs)
+    
+    def u(self, **attrs: Any) -> _TagContext:
+        """Create an u tag. Shortcut for tag('u', **attrs)."""
+        return self.tag("u", **attrs)
+    
+    def mark(self, **attrs: Any) -> _TagContext:
+        """Create a mark tag. Shortcut for tag('mark', **attrs)."""
+        return self.tag("mark", **attrs)
+    
+    def bdi(self, **attrs: Any) -> _TagContext:
+        """Create a bdi tag. Shortcut for tag('bdi', **attrs)."""
+        return self.tag("bdi", **attrs)
+    
+    def bdo(self, **attrs: Any) -> _TagContext:
+        """Create a bdo tag. Shortcut for tag('bdo', **attrs)."""
+        return self.tag("bdo", **attrs)
+    
+    def ins(self, **attrs: Any) -> _TagContext:
+        """Create an ins tag. Shortcut for tag('ins', **attrs)."""
+        return self.tag("ins", **attrs)
+    
+    def picture(self, **attrs: Any) -> _TagContext:
+        """Create a picture tag. Shortcut for tag('picture', **attrs)."""
+        return self.tag("picture", **attrs)
+    
+    def iframe(self, **attrs: Any) -> _TagContext:
+        """Create an iframe tag. Shortcut for tag('iframe', **attrs)."""
+        return self.tag("iframe", **attrs)
+    
+    def object(self, **attrs: Any) -> _TagContext:
+        """Create an object tag. Shortcut for tag('object', **attrs)."""
+        return self.tag("object", **attrs)
+    
+    def video(self, **attrs: Any) -> _TagContext:
+        """Create a video tag. Shortcut for tag('video', **attrs)."""
+        return self.tag("video", **attrs)
+    
+    def audio(self, **attrs: Any) -> _TagContext:
+        """Create an audio tag. Shortcut for tag('audio', **attrs)."""
+        return self.tag("audio", **attrs)
+    
+    def map(self, **attrs: Any) -> _TagContext:
+        """Create a map tag. Shortcut for tag('map', **attrs)."""
+        return self.tag("map", **attrs)
+    
+    def caption(self, **attrs: Any) -> _TagContext:
+        """Create a caption tag. Shortcut for tag('caption', **attrs)."""
+        return self.tag("caption", **attrs)
+    
+    def colgroup(self, **attrs: Any) -> _TagContext:
+        """Create a colgroup tag. Shortcut for tag('colgroup', **attrs)."""
+        return self.tag("colgroup", **attrs)
+    
+    def tfoot(self, **attrs: Any) -> _TagContext:
+        """Create a tfoot tag. Shortcut for tag('tfoot', **attrs)."""
+        return self.tag("tfoot", **attrs)
+    
+    def datalist(self, **attrs: Any) -> _TagContext:
+        """Create a datalist tag. Shortcut for tag('datalist', **attrs)."""
+        return self.tag("datalist", **attrs)
+    
+    def optgroup(self, **attrs: Any) -> _TagContext:
+        """Create an optgroup tag. Shortcut for tag('optgroup', **attrs)."""
+        return self.tag("optgroup", **attrs)
+    
+    def output(self, **attrs: Any) -> _TagContext:
+        """Create an output tag. Shortcut for tag('output', **attrs)."""
+        return self.tag("output", **attrs)
+    
+    def progress(self, **attrs: Any) -> _TagContext:
+        """Create a progress tag. Shortcut for tag('progress', **attrs)."""
+        return self.tag("progress", **attrs)
+    
+    def meter(self, **attrs: Any) -> _TagContext:
+        """Create a meter tag. Shortcut for tag('meter', **attrs)."""
+        return self.tag("meter", **attrs)
+    
+    def fieldset(self, **attrs: Any) -> _TagContext:
+        """Create a fieldset tag. Shortcut for tag('fieldset', **attrs)."""
+        return self.tag("fieldset", **attrs)
+    
+    def legend(self, **attrs: Any) -> _TagContext:
+        """Create a legend tag. Shortcut for tag('legend', **attrs)."""
+        return self.tag("legend", **attrs)
+    
+    def details(self, **attrs: Any) -> _TagContext:
+        """Create a details tag. Shortcut for tag('details', **attrs)."""
+        return self.tag("details", **attrs)
+    
+    def summary(self, **attrs: Any) -> _TagContext:
+        """Create a summary tag. Shortcut for tag('summary', **attrs)."""
+        return self.tag("summary", **attrs)
+    
+    def dialog(self, **attrs: Any) -> _TagContext:
+        """Create a dialog tag. Shortcut for tag('dialog', **attrs)."""
+        return self.tag("dialog", **attrs)
+    
+    def script(self, **attrs: Any) -> _TagContext:
+        """Create a script tag. Shortcut for tag('script', **attrs)."""
+        return self.tag("script", **attrs)
+    
+    def noscript(self, **attrs: Any) -> _TagContext:
+        """Create a noscript tag. Shortcut for tag('noscript', **attrs)."""
+        return self.tag("noscript", **attrs)
+    
+    def template(self, **attrs: Any) -> _TagContext:
+        """Create a template tag. Shortcut for tag('template', **attrs)."""
+        return self.tag("template", **attrs)
+    
+    def slot(self, **attrs: Any) -> _TagContext:
+        """Create a slot tag. Shortcut for tag('slot', **attrs)."""
+        return self.tag("slot", **attrs)
+    
+    def canvas(self, **attrs: Any) -> _TagContext:
+        """Create a canvas tag. Shortcut for tag('canvas', **attrs)."""
+        return self.tag("canvas", **attrs)
+    
+    def del_(self, **attrs: Any) -> _TagContext:
+        """Create a del tag. Shortcut for tag('del', **attrs)."""
+        return self.tag("del", **attrs)
+    
+    def base(self, **attrs: Any) -> None:
+        """Create a base tag. Shortcut for tag('base', **attrs).
+        
+        Note: This is a self-closing tag and doesn't need a context manager.
+        """
+        with self.tag("base", **attrs):
+            pass
+    
+    def link(self, **attrs: Any) -> None:
+        """Create a link tag. Shortcut for tag('link', **attrs).
+        
+        Note: This is a self-closing tag and doesn't need a context manager.
+        """
+        with self.tag("link", **attrs):
+            pass
+    
+    def wbr(self, **attrs: Any) -> None:
+        """Create a wbr tag. Shortcut for tag('wbr', **attrs).
+        
+        Note: This is a self-closing tag and doesn't need a context manager.
+        """
+        with self.tag("wbr", **attrs):
+            pass
+    
+    def area(self, **attrs: Any) -> None:
+        """Create an area tag. Shortcut for tag('area', **attrs).
+        
+        Note: This is a self-closing tag and doesn't need a context manager.
+        """
+        with self.tag("area", **attrs):
+            pass
+    
+    def col(self, **attrs: Any) -> None:
+        """Create a col tag. Shortcut for tag('col', **attrs).
+        
+        Note: This is a self-closing tag and doesn't need a context manager.
+        """
+        with self.tag("col", **attrs):
+            pass
+    
+    def embed(self, **attrs: Any) -> None:
+        """Create an embed tag. Shortcut for tag('embed', **attrs).
+        
+        Note: This is a self-closing tag and doesn't need a context manager.
+        """
+        with self.tag("embed", **attrs):
+            pass
+    
+    def source(self, **attrs: Any) -> None:
+        """Create a source tag. Shortcut for tag('source', **attrs).
+        
+        Note: This is a self-closing tag and doesn't need a context manager.
+        """
+        with self.tag("source", **attrs):
+            pass
+    
+    def track(self, **attrs: Any) -> None:
+        """Create a track tag. Shortcut for tag('track', **attrs).
+        
+        Note: This is a self-closing tag and doesn't need a context manager.
+        """
+        with self.tag("track", **attrs):
+            pass
+    
+    # Shortcut methods for MathML elements
+    
+    def math(self, **attrs: Any) -> _TagContext:
+        """Create a MathML math root tag. Shortcut for tag('math', **attrs).
+        
+        The MathML namespace is declared via ``xmlns`` unless another value
+        (or None to omit it) is given, so the output is also valid XML.
+        """
+        attrs.setdefault("xmlns", MATHML_NAMESPACE)
+        return self.tag("math", **attrs)
+    
+    def mi(self, **attrs: Any) -> _TagContext:
+        """Create a MathML mi tag. Shortcut for tag('mi', **attrs)."""
+        return self.tag("mi", **attrs)
+    
+    def mn(self, **attrs: Any) -> _TagContext:
+        """Create a MathML mn tag. Shortcut for tag('mn', **attrs)."""
+        return self.tag("mn", **attrs)
+    
+    def mo(self, **attrs: Any) -> _TagContext:
+        """Create a MathML mo tag. Shortcut for tag('mo', **attrs)."""
+        return self.tag("mo", **attrs)
+    
+    def ms(self, **attrs: Any) -> _TagContext:
+        """Create a MathML ms tag. Shortcut for tag('ms', **attrs)."""
+        return self.tag("ms", **attrs)
+    
+    def mtext(self, **attrs: Any) -> _TagContext:
+        """Create a MathML mtext tag. Shortcut for tag('mtext', **attrs)."""
+        return self.tag("mtext", **attrs)
+    
+    def mrow(self, **attrs: Any) -> _TagContext:
+        """Create a MathML mrow tag. Shortcut for tag('mrow', **attrs)."""
+        return self.tag("mrow", **attrs)
+    
+    def mfrac(self, **attrs: Any) -> _TagContext:
+        """Create a MathML mfrac tag. Shortcut for tag('mfrac', **attrs)."""
+        return self.tag("mfrac", **attrs)
+    
+    def msqrt(self, **attrs: Any) -> _TagContext:
+        """Create a MathML msqrt tag. Shortcut for tag('msqrt', **attrs)."""
+        return self.tag("msqrt", **attrs)
+    
+    def mroot(self, **attrs: Any) -> _TagContext:
+        """Create a MathML mroot tag. Shortcut for tag('mroot', **attrs)."""
+        return self.tag("mroot", **attrs)
+    
+    def mstyle(self, **attrs: Any) -> _TagContext:
+        """Create a MathML mstyle tag. Shortcut for tag('mstyle', **attrs)."""
+        return self.tag("mstyle", **attrs)
+    
+    def merror(self, **attrs: Any) -> _TagContext:
+        """Create a MathML merror tag. Shortcut for tag('merror', **attrs)."""
+        return self.tag("merror", **attrs)
+    
+    def mpadded(self, **attrs: Any) -> _TagContext:
+        """Create a MathML mpadded tag. Shortcut for tag('mpadded', **attrs)."""
+        return self.tag("mpadded", **attrs)
+    
+    def mphantom(self, **attrs: Any) -> _TagContext:
+        """Create a MathML mphantom tag. Shortcut for tag('mphantom', **attrs)."""
+        return self.tag("mphantom", **attrs)
+    
+    def menclose(self, **attrs: Any) -> _TagContext:
+        """Create a MathML menclose tag. Shortcut for tag('menclose', **attrs)."""
+        return self.tag("menclose", **attrs)
+    
+    def msub(self, **attrs: Any) -> _TagContext:
+        """Create a MathML msub tag. Shortcut for tag('msub', **attrs)."""
+        return self.tag("msub", **attrs)
+    
+    def msup(self, **attrs: Any) -> _TagContext:
+        """Create a MathML msup tag. Shortcut for tag('msup', **attrs)."""
+        return self.tag("msup", **attrs)
+    
+    def msubsup(self, **attrs: Any) -> _TagContext:
+        """Create a MathML msubsup tag. Shortcut for tag('msubsup', **attrs)."""
+        return self.tag("msubsup", **attrs)
+    
+    def munder(self, **attrs: Any) -> _TagContext:
+        """Create a MathML munder tag. Shortcut for tag('munder', **attrs)."""
+        return self.tag("munder", **attrs)
+    
+    def mover(self, **attrs: Any) -> _TagContext:
+        """Create a MathML mover tag. Shortcut for tag('mover', **attrs)."""
+        return self.tag("mover", **attrs)
+    
+    def munderover(self, **attrs: Any) -> _TagContext:
+        """Create a MathML munderover tag. Shortcut for tag('munderover', **attrs)."""
+        return self.tag("munderover", **attrs)
+    
+    def mmultiscripts(self, **attrs: Any) -> _TagContext:
+        """Create a MathML mmultiscripts tag. Shortcut for tag('mmultiscripts', **attrs)."""
+        return self.tag("mmultiscripts", **attrs)
+    
+    def mtable(self, **attrs: Any) -> _TagContext:
+        """Create a MathML mtable tag. Shortcut for tag('mtable', **attrs)."""
+        return self.tag("mtable", **attrs)
+    
+    def mtr(self, **attrs: Any) -> _TagContext:
+        """Create a MathML mtr tag. Shortcut for tag('mtr', **attrs)."""
+        return self.tag("mtr", **attrs)
+    
+    def mtd(self, **attrs: Any) -> _TagContext:
+        """Create a MathML mtd tag. Shortcut for tag('mtd', **attrs)."""
+        return self.tag("mtd", **attrs)
+    
+    def semantics(self, **attrs: Any) -> _TagContext:
+        """Create a MathML semantics tag. Shortcut for tag('semantics', **attrs)."""
+        return self.tag("semantics", **attrs)
+    
+    def annotation(self, **attrs: Any) -> _TagContext:
+        """Create a MathML annotation tag. Shortcut for tag('annotation', **attrs)."""
+        return self.tag("annotation", **attrs)
+    
+    def annotation_xml(self, **attrs: Any) -> _TagContext:
+        """Create a MathML annotation-xml tag. Shortcut for tag('annotation-xml', **attrs)."""
+        return self.tag("annotation-xml", **attrs)
+    
+    def mspace(self, **attrs: Any) -> None:
+        """Create a MathML mspace tag. Shortcut for tag('mspace', **attrs).
+        
+        Note: This is an empty element and doesn't need a context manager.
+        """
+        with self.tag("mspace", **attrs):
+            pass
+    
+    def mprescripts(self, **attrs: Any) -> None:
+        """Create a MathML mprescripts tag. Shortcut for tag('mprescripts', **attrs).
+        
+        Note: This is an empty element and doesn't need a context manager.
+        """
+        with self.tag("mprescripts", **attrs):
+            pass
+
+
+def _markup_of(content: Document | Fragment | Element | str) -> str:
+    """Return the HTML of a built document, fragment, tree element or string."""
+    if isinstance(content, Document):
+        return content.render()
+    if isinstance(content, (Fragment, Element)):
+        return str(content)
+    if isinstance(content, str):
+        return content
+    raise TypeError(
+        f"Expected a Document, Fragment, Element or str, got {type(content).__name__}"
+    )
+
+
+def turbo_stream(
+    action: str,
+    content: Document | Fragment | Element | str | None = None,
+    target: str | None = None,
+    targets: str | None = None,
+) -> Fragment:
+    """Wrap content in a Turbo Stream envelope.
+    
+    Args:
+        action: The stream action (append, prepend, replace, update,
+            remove, before, after, refresh...)
+        content: The markup wrapped in the stream's <template>; omitted for
+            actions without content such as remove
+        target: The id of the element to act on
+        targets: A CSS selector of the elements to act on, instead of target
+    
+    Raises:
+        ValueError: If not exactly one of target and targets is given
+            (except for the refresh action)
+    
+    Example:
+        turbo_stream("append", row_doc, target="messages")
+        # <turbo-stream action="append" target="messages"><template>...</template></turbo-stream>
+    """
+    if action != "refresh" and (target is None) == (targets is None):
+        raise ValueError("turbo_stream() requires exactly one of target or targets")
+    attrs = _format_attrs({"action": action, "target": target, "targets": targets})
+    template = f"<template>{_markup_of(content)}</template>" if content is not None else ""
+    return Fragment(f"<turbo-stream{attrs}>{template}</turbo-stream>")
+
+
+def sse_event(
+    content: Document | Fragment | Element | str,
+    event: str | None = None,
+    id: str | None = None,
+    retry: int | None = None,
+) -> str:
+    """Format content as a Server-Sent Events message.
+    
+    Each line of the markup becomes its own ``data:`` field, so fragments
+    containing newlines are transmitted intact, and the message ends with
+    the blank line that dispatches it.
+    
+    Args:
+        content: The markup to send
+        event: The event name, for EventSource listeners other than "message"
+        id: The event id, sent back by the browser as Last-Event-ID
+        retry: Reconnection time in milliseconds
+    
+    Raises:
+        ValueError: If event or id contains a line break
+    
+    Example:
+        yield sse_event(doc.fragment(), event="notification")
+        # event: notification\ndata: <div>...</div>\n\n
+    """
+    lines: list[str] = []
+    for name, value in (("event", event), ("id", id)):
+        if value is not None:
+            if "\n" in value or "\r" in value:
+                raise ValueError(f"SSE {name} must not contain line breaks")
+            lines.append(f"{name}: {value}")
+    if retry is not None:
+        lines.append(f"retry: {int(retry)}")
+    for line in _markup_of(content).replace("\r\n", "\n").replace("\r", "\n").split("\n"):
+        lines.append(f"data: {line}")
+    return "\n".join(lines) + "\n\n"
 
 
 # Convenience function for creating documents